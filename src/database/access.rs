@@ -0,0 +1,138 @@
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::database::db::{AQuery, Database, GQuery};
+use crate::database::row::{FromRow, Row, RowError};
+use crate::error::Error;
+use crate::from_row;
+
+const MAX_LIMIT: i64 = 1000;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct AccessEntry {
+    pub timestamp: u64,
+    pub ip: Option<String>,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    pub bytes: u64,
+    pub duration_ms: u64,
+    pub username: Option<String>,
+    pub user_agent: Option<String>
+}
+
+from_row!(AccessEntry { timestamp, ip, method, path, status, bytes, duration_ms, username, user_agent });
+
+impl AccessEntry {
+    pub fn as_query(&self) -> AQuery {
+        AQuery::Access {
+            timestamp: self.timestamp as i64,
+            ip: self.ip.clone(),
+            method: self.method.clone(),
+            path: self.path.clone(),
+            status: self.status as i64,
+            bytes: self.bytes as i64,
+            duration_ms: self.duration_ms as i64,
+            username: self.username.clone(),
+            user_agent: self.user_agent.clone()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AccessFilter { // Unset fields match every row
+    pub since: Option<i64>, // Inclusive
+    pub until: Option<i64>, // Exclusive
+    pub status_class: Option<i64>, // 4 matches 400 to 499
+    pub path_prefix: Option<String>,
+    pub ip: Option<String>,
+    pub username: Option<String>
+}
+
+impl AccessFilter {
+    pub fn from_json(json: &Value) -> Self {
+        let text = |key: &str| json.get(key).and_then(|value| value.as_str()).map(|s| s.to_string());
+        Self {
+            since: json.get("since").and_then(|value| value.as_i64()),
+            until: json.get("until").and_then(|value| value.as_i64()),
+            status_class: json.get("status").and_then(|value| value.as_i64()),
+            path_prefix: text("path"),
+            ip: text("ip"),
+            username: text("user")
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct MinuteStats {
+    pub minute: u64, // Unix timestamp of the start of the minute
+    pub requests: u64,
+    pub client_errors: u64,
+    pub server_errors: u64
+}
+
+from_row!(MinuteStats { minute, requests, client_errors, server_errors });
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PathStats {
+    pub path: String,
+    pub requests: u64,
+    pub client_errors: u64,
+    pub server_errors: u64
+}
+
+from_row!(PathStats { path, requests, client_errors, server_errors });
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct AccessSummary {
+    pub requests: u64,
+    pub client_errors: u64,
+    pub server_errors: u64,
+    pub error_rate: f64, // Share of requests answered with 4xx or 5xx
+    pub bytes: u64,
+    pub average_ms: f64
+}
+
+impl FromRow for AccessSummary {
+    fn from_row(row: &Row) -> Result<Self, RowError> {
+        let requests: u64 = row.get("requests")?;
+        let client_errors: u64 = row.get("client_errors")?;
+        let server_errors: u64 = row.get("server_errors")?;
+        let duration_ms: u64 = row.get("duration_ms")?;
+        let share = |part: u64| if requests == 0 { 0.0 } else { part as f64 / requests as f64 };
+        Ok(Self {
+            requests,
+            client_errors,
+            server_errors,
+            error_rate: share(client_errors + server_errors),
+            bytes: row.get("bytes")?,
+            average_ms: share(duration_ms)
+        })
+    }
+}
+
+impl Database { // Reading back the access log written by server::access_log
+    pub fn access_entries(&self, filter: &AccessFilter, limit: i64, offset: i64) -> Result<Vec<AccessEntry>, Error> { // Newest first
+        self.fetch(&GQuery::AccessEntries {
+            filter: filter.clone(),
+            limit: limit.clamp(0, MAX_LIMIT),
+            offset: offset.max(0)
+        })
+    }
+
+    pub fn access_per_minute(&self, filter: &AccessFilter) -> Result<Vec<MinuteStats>, Error> { // Oldest first, minutes without requests are left out
+        self.fetch(&GQuery::AccessPerMinute { filter: filter.clone() })
+    }
+
+    pub fn access_top_paths(&self, filter: &AccessFilter, limit: i64) -> Result<Vec<PathStats>, Error> { // Busiest first
+        self.fetch(&GQuery::AccessTopPaths {
+            filter: filter.clone(),
+            limit: limit.clamp(0, MAX_LIMIT)
+        })
+    }
+
+    pub fn access_summary(&self, filter: &AccessFilter) -> Result<AccessSummary, Error> {
+        self.first(&GQuery::AccessSummary { filter: filter.clone() })?
+            .ok_or_else(|| RowError::Missing(String::from("requests")).into())
+    }
+}
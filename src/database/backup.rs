@@ -0,0 +1,108 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::database::db::Database;
+use crate::database::pool::PoolError;
+use crate::tools::utils::{now, utc_stamp};
+
+#[derive(Debug)]
+pub enum BackupError {
+    Unsupported(&'static str), // The backend has no online backup
+    NotFound(String), // No database by that name
+    Unavailable(PoolError),
+    Io(std::io::Error),
+    Sqlite(rusqlite::Error),
+    Corrupt(String), // What integrity_check reported instead of ok
+    Version {
+        backup: i64,
+        live: i64
+    } // Taken at another schema version, restoring it would skip or undo migrations
+}
+
+impl std::fmt::Display for BackupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BackupError::Unsupported(backend) => write!(f, "{} databases have no online backup or restore", backend),
+            BackupError::NotFound(name) => write!(f, "no database named {}", name),
+            BackupError::Unavailable(e) => write!(f, "{}", e),
+            BackupError::Io(e) => write!(f, "{}", e),
+            BackupError::Sqlite(e) => write!(f, "{}", e),
+            BackupError::Corrupt(report) => write!(f, "integrity check failed: {}", report),
+            BackupError::Version { backup, live } => write!(f, "backup is at schema version {} but the database is at {}", backup, live)
+        }
+    }
+}
+
+impl std::error::Error for BackupError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BackupError::Unavailable(e) => Some(e),
+            BackupError::Io(e) => Some(e),
+            BackupError::Sqlite(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<PoolError> for BackupError {
+    fn from(e: PoolError) -> Self {
+        BackupError::Unavailable(e)
+    }
+}
+
+impl From<std::io::Error> for BackupError {
+    fn from(e: std::io::Error) -> Self {
+        BackupError::Io(e)
+    }
+}
+
+impl From<rusqlite::Error> for BackupError {
+    fn from(e: rusqlite::Error) -> Self {
+        BackupError::Sqlite(e)
+    }
+}
+
+impl Database { // Copies taken while the server keeps running, see DatabaseConnection::backup
+    pub fn backup(&self, dir: &Path, keep: usize) -> Result<PathBuf, BackupError> { // -> dir/<name>-<utc time>.db, then only the newest keep are left, 0 keeps all
+        fs::create_dir_all(dir)?;
+        let path = dir.join(format!("{}-{}.db", self.name, utc_stamp(now())));
+        let partial = path.with_extension("partial"); // Renamed once complete, so a half-written copy is never taken for a backup
+
+        if let Err(e) = self.backend()?.backup(&partial) {
+            let _ = fs::remove_file(&partial);
+            return Err(e);
+        }
+        fs::rename(&partial, &path)?;
+
+        if keep > 0 {
+            let backups = self.backups(dir);
+            for old in &backups[..backups.len().saturating_sub(keep)] {
+                if let Err(e) = fs::remove_file(old) {
+                    println!("Failed to remove old backup {}: {}", old.display(), e);
+                }
+            }
+        }
+        Ok(path)
+    }
+
+    pub fn backups(&self, dir: &Path) -> Vec<PathBuf> { // Oldest first
+        let prefix = format!("{}-", self.name);
+        let mut backups: Vec<PathBuf> = match fs::read_dir(dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.extension().is_some_and(|extension| extension == "db"))
+                .filter(|path| path.file_name().and_then(|name| name.to_str()).is_some_and(|name| name.starts_with(&prefix)))
+                .collect(),
+            Err(_) => Vec::new()
+        };
+        backups.sort();
+        backups
+    }
+
+    pub fn restore(&self, source: &Path) -> Result<(), BackupError> { // Checked before anything is overwritten, open connections stay usable
+        if !source.is_file() {
+            return Err(BackupError::Io(std::io::Error::new(std::io::ErrorKind::NotFound, format!("{} not found", source.display()))));
+        }
+        self.backend()?.restore(source)
+    }
+}
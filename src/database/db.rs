@@ -1,26 +1,199 @@
-use std::{error::Error, path::PathBuf};
-use rusqlite::types::FromSql;
+use std::{collections::HashMap, panic::{self, AssertUnwindSafe}, path::Path, sync::Arc, time::Duration};
+use rusqlite::types::{FromSql, Value, ValueRef};
+use serde::Deserialize;
 
+use crate::database::access::AccessFilter;
+use crate::database::backup::BackupError;
+use crate::database::fields::{EncryptedColumn, FieldCipher, IndexUpdate};
+use crate::database::migrations::{Migration, MigrationError};
+use crate::database::pool::{Checkout, Connector, Pool, PoolError};
+use crate::database::row::{FromRow, Row, RowError};
 use crate::database::sqlite::Sqlite;
-use crate::tools::config::DType;
-use rusqlite::Connection;
+use crate::error::Error;
+use crate::login::keyring::Keyring;
+use crate::tools::config::{get_integer, DType};
+use crate::tools::filesystem::FileSystem;
 
 use super::textfile::TextFile;
 
 pub struct DatabaseStruct<'a> {
     pub src: &'a str,
-    pub items: Vec<(String, DType)>,
-    pub onload: &'a str
+    pub tables: Vec<TableStruct>, // The current schema, new databases are created from it
+    pub migrations: Vec<Migration>, // Bring older databases up to the current schema
+    pub onload: fn() -> Result<Vec<AQuery>, Error> // Seed rows, built and written as-is only when the database is created
 }
 
-pub trait DatabaseConnection {
-    fn open() -> bool;
-    fn add(query: AQuery) -> bool;
-    fn remove() -> bool;
-    fn get(query: GQuery) -> Option<String>;
-    fn wipe() -> bool;
+pub struct TableStruct {
+    pub name: &'static str,
+    pub columns: Vec<ColumnStruct>,
+    pub primary_key: Vec<&'static str>, // Left empty when a column is auto incremented
+    pub indexes: Vec<IndexStruct>
 }
 
+pub struct ColumnStruct {
+    pub name: &'static str,
+    pub dtype: DType,
+    pub nullable: bool,
+    pub unique: bool,
+    pub default: Option<&'static str>, // SQL literal
+    pub auto_increment: bool,
+    pub encrypted: bool, // Needs a keyring via Database::with_keyring
+    pub blind_index: Option<&'static str> // Column holding an HMAC of an encrypted value, for lookups
+}
+
+pub struct IndexStruct {
+    pub name: &'static str,
+    pub columns: Vec<&'static str>,
+    pub unique: bool
+}
+
+impl DatabaseStruct<'_> {
+    pub fn encrypted_columns(&self) -> Vec<EncryptedColumn> {
+        self.tables
+            .iter()
+            .flat_map(|table| table.columns
+                .iter()
+                .filter(|column| column.encrypted)
+                .map(|column| match column.blind_index {
+                    Some(index) => EncryptedColumn::indexed(table.name, column.name, index),
+                    None => EncryptedColumn::new(table.name, column.name)
+                }))
+            .collect()
+    }
+}
+
+impl TableStruct {
+    pub fn new(name: &'static str, columns: Vec<ColumnStruct>) -> Self {
+        Self {
+            name,
+            columns,
+            primary_key: Vec::new(),
+            indexes: Vec::new()
+        }
+    }
+
+    pub fn primary_key(mut self, columns: &[&'static str]) -> Self {
+        self.primary_key = columns.to_vec();
+        self
+    }
+
+    pub fn index(mut self, name: &'static str, columns: &[&'static str], unique: bool) -> Self {
+        self.indexes.push(IndexStruct { name, columns: columns.to_vec(), unique });
+        self
+    }
+
+    pub fn as_sql(&self) -> Vec<String> { // CREATE TABLE followed by its CREATE INDEX statements
+        let mut definitions: Vec<String> = self.columns.iter().map(ColumnStruct::as_sql).collect();
+        if !self.primary_key.is_empty() {
+            definitions.push(format!("PRIMARY KEY ({})", self.primary_key.join(", ")));
+        }
+
+        let mut statements = vec![format!("CREATE TABLE IF NOT EXISTS {} ({})", self.name, definitions.join(", "))];
+        for index in &self.indexes {
+            statements.push(format!(
+                "CREATE {}INDEX IF NOT EXISTS {} ON {} ({})",
+                if index.unique { "UNIQUE " } else { "" },
+                index.name,
+                self.name,
+                index.columns.join(", ")
+            ));
+        }
+        statements
+    }
+}
+
+impl ColumnStruct {
+    pub fn new(name: &'static str, dtype: DType) -> Self {
+        Self {
+            name,
+            dtype,
+            nullable: true,
+            unique: false,
+            default: None,
+            auto_increment: false,
+            encrypted: false,
+            blind_index: None
+        }
+    }
+
+    pub fn not_null(mut self) -> Self {
+        self.nullable = false;
+        self
+    }
+
+    pub fn unique(mut self) -> Self {
+        self.unique = true;
+        self
+    }
+
+    pub fn default(mut self, value: &'static str) -> Self {
+        self.default = Some(value);
+        self
+    }
+
+    pub fn auto_increment(mut self) -> Self { // INTEGER PRIMARY KEY AUTOINCREMENT
+        self.auto_increment = true;
+        self
+    }
+
+    pub fn encrypted(mut self, blind_index: Option<&'static str>) -> Self {
+        self.encrypted = true;
+        self.blind_index = blind_index;
+        self
+    }
+
+    pub fn as_sql(&self) -> String {
+        let mut sql = format!("{} {}", self.name, self.dtype.as_sql());
+        if self.auto_increment {
+            sql.push_str(" PRIMARY KEY AUTOINCREMENT");
+        }
+        if !self.nullable {
+            sql.push_str(" NOT NULL");
+        }
+        if self.unique {
+            sql.push_str(" UNIQUE");
+        }
+        if let Some(default) = self.default {
+            sql.push_str(&format!(" DEFAULT {}", default));
+        }
+        sql
+    }
+}
+
+pub struct Rows { // What a backend hands back, converted to the caller's type by Database
+    pub columns: Vec<String>,
+    pub values: Vec<Vec<Value>>
+}
+
+pub trait DatabaseConnection: Send { // A storage backend, Database dispatches every query through one
+    fn name(&self) -> &'static str;
+    fn init(&self, this_db: &DatabaseStruct) -> Result<(), MigrationError>; // Creates or migrates the tables
+    fn add(&self, query: &AQuery, indexes: &[IndexUpdate]) -> Result<(), Error>;
+    fn get(&self, query: &GQuery, fields: &FieldCipher) -> Result<Rows, Error>;
+    fn remove(&self, table: &str, column: &str, value: &str) -> Result<(), Error>; // Deletes the rows where column = value
+    fn wipe(&self) -> Result<(), Error>; // Deletes every row but keeps the tables
+    fn begin(&self) -> Result<(), Error>;
+    fn commit(&self) -> Result<(), Error>;
+    fn rollback(&self) -> Result<(), Error>;
+    fn savepoint(&self, name: &str) -> Result<(), Error>; // Only inside a transaction, names are released in reverse order
+    fn release(&self, name: &str) -> Result<(), Error>;
+    fn rollback_to(&self, name: &str) -> Result<(), Error>; // Undoes everything since the savepoint and releases it
+
+    fn query(&self, _sql: &str) -> Result<Rows, Error> { // Raw SQL, for backends that speak it
+        Err(Error::database(format!("{} does not support raw queries", self.name())))
+    }
+
+    fn backup(&self, _destination: &Path) -> Result<(), BackupError> { // A consistent copy taken while other connections keep writing
+        Err(BackupError::Unsupported(self.name()))
+    }
+
+    fn restore(&mut self, _source: &Path) -> Result<(), BackupError> { // Replaces the contents with a verified backup
+        Err(BackupError::Unsupported(self.name()))
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(tag = "query")] // Fixtures spell each one as {"query": "User", "username": ..., ...}
 pub enum AQuery {
     User {
         username: String,
@@ -37,95 +210,575 @@ pub enum AQuery {
     UserPing {
         username: String,
         site: String,
-    } // username, site
+    }, // username, site
+    Password {
+        username: String,
+        password: String,
+    }, // username, password
+    Lock {
+        username: String,
+        until: i64,
+    }, // username, unix timestamp
+    Unlock {
+        username: String,
+    }, // username
+    Event {
+        timestamp: i64,
+        kind: String,
+        ip: Option<String>,
+        username: Option<String>,
+        detail: Option<String>,
+    }, // timestamp, kind, opt<ip>, opt<username>, opt<detail>
+    Session {
+        token: String,
+        username: String,
+        created: i64,
+        expires: i64,
+    }, // token hash, username, created, expires
+    EndSession {
+        token: String,
+    }, // token hash
+    Role {
+        username: String,
+        role: String,
+    }, // username, role
+    RevokeRole {
+        username: String,
+        role: String,
+    }, // username, role
+    SigningKey {
+        kid: String,
+        key: String,
+        created: i64,
+    }, // kid, base64 key, created
+    RetireSigningKeys {
+        retired: i64,
+    }, // retires every active key at the given time
+    SigningKeyRewrap {
+        kid: String,
+        key: String,
+    }, // kid, key re-encrypted under the active data key
+    TotpSecret {
+        username: String,
+        secret: String,
+    }, // username, encrypted secret (pending until TotpEnable)
+    TotpRewrap {
+        username: String,
+        secret: String,
+    }, // username, secret re-encrypted under the active key
+    TotpEnable {
+        username: String,
+    }, // username
+    TotpStep {
+        username: String,
+        step: i64,
+    }, // username, last accepted step
+    TotpDisable {
+        username: String,
+    }, // username
+    RecoveryCode {
+        username: String,
+        code: String,
+    }, // username, code hash
+    UseRecoveryCode {
+        username: String,
+        code: String,
+    }, // username, code hash
+    ClearRecoveryCodes {
+        username: String,
+    }, // username
+    Challenge {
+        token: String,
+        username: String,
+        grant: String,
+        expires: i64,
+    }, // token hash, username, grant, expires
+    EndChallenge {
+        token: String,
+    }, // token hash
+    EndSessions {
+        username: String,
+    }, // username
+    Reset {
+        token: String,
+        username: String,
+        expires: i64,
+    }, // token hash, username, expires
+    EndResets {
+        username: String,
+    }, // username
+    ApiKey {
+        id: String,
+        username: String,
+        label: String,
+        hash: String,
+        scopes: String,
+        created: i64,
+    }, // id, owner, label, key hash, comma separated scopes, created
+    RevokeApiKey {
+        id: String,
+        username: String,
+    }, // id, owner
+    ApiKeyUsed {
+        hash: String,
+        used: i64,
+    }, // key hash, last used
+    Profile {
+        username: String,
+        name: Option<String>,
+        email: Option<String>,
+        site: Option<String>,
+    }, // username, opt<name>, opt<email>, opt<site>, unset fields are kept
+    DeleteUser {
+        username: String,
+    }, // username, the users row only
+    RevokeRoles {
+        username: String,
+    }, // username
+    DeleteApiKeys {
+        username: String,
+    }, // owner
+    #[serde(skip)] // Only ever built from the schema, never from a fixture
+    FieldRewrap {
+        column: EncryptedColumn,
+        ciphertext: String,
+        rotated: String,
+        index: Option<String>,
+    }, // encrypted column, current value, the same value under the active key, opt<blind index> when it was stored as plaintext
+    RevokeApiKeys {
+        username: String,
+    }, // owner, every key
+    RevokeTokens {
+        username: String,
+        before: i64,
+    }, // username, bearer tokens issued at or before the timestamp are refused
+    Access {
+        timestamp: i64,
+        ip: Option<String>,
+        method: String,
+        path: String,
+        status: i64,
+        bytes: i64,
+        duration_ms: i64,
+        username: Option<String>,
+        user_agent: Option<String>,
+    }, // timestamp, opt<ip>, method, path, status, response bytes, duration, opt<username>, opt<user agent>
+    PruneAccess {
+        before: i64,
+    } // deletes access log rows older than the timestamp
 }
 
+#[derive(Clone)]
 pub enum GQuery {
     Password {
         username: String,
     }, // username -> [password]
     UserData {
         username: String,
-    } // username -> [name, email]
+    }, // username -> [name, email]
+    Email {
+        email: String,
+    }, // email -> [username]
+    User {
+        username: String,
+    }, // username -> [username, name, email, site]
+    Status {
+        username: String,
+    }, // username -> [locked, disabled, locked_until]
+    Session {
+        token: String,
+        now: i64,
+    }, // token hash, now -> [username]
+    Roles {
+        username: String,
+    }, // username -> [role]
+    ActiveSigningKey, // -> [kid, key]
+    SigningKey {
+        kid: String,
+        since: i64,
+    }, // kid, oldest retirement still accepted -> [key]
+    TotpSecret {
+        username: String,
+    }, // username -> [encrypted secret]
+    TotpState {
+        username: String,
+    }, // username -> [enabled, last_step]
+    RecoveryCode {
+        username: String,
+        code: String,
+    }, // username, code hash -> [code]
+    Challenge {
+        token: String,
+        now: i64,
+    }, // token hash, now -> [username, grant]
+    Reset {
+        token: String,
+        now: i64,
+    }, // token hash, now -> [username]
+    ApiKey {
+        hash: String,
+    }, // key hash -> [username, scopes]
+    ApiKeys {
+        username: String,
+    }, // owner -> [id, label, scopes, created, last_used, revoked]
+    Users {
+        order: UserOrder,
+        descending: bool,
+        limit: i64,
+        offset: i64,
+    }, // order, descending, page size, rows skipped -> [username, name, email, site]
+    UserCount, // -> [count]
+    UserExists {
+        username: String,
+    }, // username -> [exists]
+    TokensRevoked {
+        username: String,
+    }, // username -> [tokens_revoked]
+    PlaintextFields {
+        column: EncryptedColumn,
+    }, // encrypted column -> [value] of every value still marked as baseline plaintext
+    AccessEntries {
+        filter: AccessFilter,
+        limit: i64,
+        offset: i64,
+    }, // filter, page size, rows skipped -> [timestamp, ip, method, path, status, bytes, duration_ms, username, user_agent]
+    AccessPerMinute {
+        filter: AccessFilter,
+    }, // filter -> [minute, requests, client_errors, server_errors]
+    AccessTopPaths {
+        filter: AccessFilter,
+        limit: i64,
+    }, // filter, paths returned -> [path, requests, client_errors, server_errors]
+    AccessSummary {
+        filter: AccessFilter,
+    } // filter -> [requests, client_errors, server_errors, duration_ms, bytes]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UserOrder { // Encrypted columns have no useful order, so only plain ones are offered
+    Username,
+    Site
+}
+
+impl UserOrder {
+    pub fn column(&self) -> &'static str {
+        match self {
+            UserOrder::Username => "username",
+            UserOrder::Site => "site"
+        }
+    }
+}
+
+impl AQuery {
+    pub fn fields_mut(&mut self) -> Vec<(&'static str, &'static str, &mut String)> { // (table, column, value) of the user data written
+        match self {
+            AQuery::User { name, email, site, .. } => [("name", name), ("email", email), ("site", site)]
+                .into_iter()
+                .filter_map(|(column, value)| value.as_mut().map(|value| ("users", column, value)))
+                .collect(),
+            AQuery::UserPing { site, .. } => vec![("users", "site", site)],
+            AQuery::Profile { name, email, site, .. } => [("name", name), ("email", email), ("site", site)]
+                .into_iter()
+                .filter_map(|(column, value)| value.as_mut().map(|value| ("users", column, value)))
+                .collect(),
+            _ => vec![]
+        }
+    }
 }
 
-#[derive(Debug)]
+impl GQuery {
+    pub fn lookup_mut(&mut self) -> Option<(&'static str, &'static str, &mut String)> { // (table, column, value) of a lookup by user data
+        match self {
+            GQuery::Email { email } => Some(("users", "email", email)),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum DatabaseType {
-    Sqlite(Connection),
-    Textfile(PathBuf),
-    None
+    Sqlite, // <src>.db, falling back to the textfile when fail safe
+    Textfile, // <src>.txt
+    Memory // SQLite in memory, for tests and throwaway deployments
+}
+
+impl DatabaseType {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "sqlite" => Some(DatabaseType::Sqlite),
+            "textfile" => Some(DatabaseType::Textfile),
+            "memory" => Some(DatabaseType::Memory),
+            _ => None
+        }
+    }
 }
 
-#[derive(Debug)]
 pub struct Database {
-    pub conn: DatabaseType
+    pub name: String, // this_db.src, names its backups
+    pub pool: Pool,
+    pub fields: FieldCipher
+}
+
+pub struct Lease<'a> { // Keeps the thread on one connection until dropped
+    pool: &'a Pool
+}
+
+impl Drop for Lease<'_> {
+    fn drop(&mut self) {
+        self.pool.unpin();
+    }
 }
 
 impl Database {
-    pub fn connect(this_db: DatabaseStruct, fail_safe: bool) -> Self { //Fail safe switches to textfile database if it cannot find the sql server
-        match Sqlite::open(format!("{}.db", this_db.src).as_str()) {
-            Ok(connection) => {
-                if Sqlite::init(&connection, this_db) {
-                    Self { conn: DatabaseType::Sqlite(connection) }
-                } else {
-                    Self { conn: DatabaseType::None }
+    pub fn connect(this_db: DatabaseStruct, fail_safe: bool) -> Result<Self, Error> { //Fail safe switches to textfile database if it cannot find the sql server
+        Self::open(DatabaseType::Sqlite, this_db, fail_safe)
+    }
+
+    pub fn open(kind: DatabaseType, this_db: DatabaseStruct, fail_safe: bool) -> Result<Self, Error> {
+        let path = format!("{}.db", this_db.src);
+        let sqlite = move || -> Option<Box<dyn DatabaseConnection>> {
+            match Sqlite::open(path.as_str()) {
+                Ok(sqlite) => Some(Box::new(sqlite)),
+                Err(_) => None
+            }
+        };
+        let textfile = || -> Result<Box<dyn DatabaseConnection>, Error> {
+            Ok(Box::new(TextFile::open(format!("{}.txt", this_db.src).as_str())?))
+        };
+        let (conn, connect): (Box<dyn DatabaseConnection>, Option<Connector>) = match kind { // Only SQLite files can be opened more than once
+            DatabaseType::Sqlite => match sqlite() {
+                Some(conn) => (conn, Some(Box::new(sqlite))),
+                None if fail_safe => (textfile()?, None),
+                None => return Err(Error::Config(format!("db/{}.db is missing", this_db.src)))
+            },
+            DatabaseType::Textfile => (textfile()?, None),
+            DatabaseType::Memory => (Box::new(Sqlite::memory()?), None)
+        };
+        Self::start(conn, connect, this_db)
+    }
+
+    pub fn with_backend(conn: Box<dyn DatabaseConnection>, this_db: DatabaseStruct) -> Result<Self, Error> { // For plugging in other storage
+        Self::start(conn, None, this_db)
+    }
+
+    fn start(conn: Box<dyn DatabaseConnection>, connect: Option<Connector>, this_db: DatabaseStruct) -> Result<Self, Error> { // Migrates through the first connection, the pool opens the rest as needed
+        conn.init(&this_db)?; // A database written by a newer build is refused here rather than touched
+        let max_size = get_integer("database_pool_size") as usize;
+        let timeout = Duration::from_millis(get_integer("database_checkout_timeout_ms"));
+        Ok(Self {
+            name: this_db.src.to_string(),
+            pool: Pool::new(conn, connect, max_size, timeout),
+            fields: FieldCipher::new(this_db.encrypted_columns())
+        })
+    }
+
+    pub fn with_keyring(mut self, keyring: Arc<Keyring>) -> Result<Self, Error> { // Keys for the encrypted columns, encrypts anything migrated from plaintext
+        self.fields.set_keyring(keyring);
+        for column in self.fields.columns() {
+            self.retrieve(&GQuery::PlaintextFields { column: column.clone() })?; // Reading is enough, decrypt writes them back encrypted
+        }
+        Ok(self)
+    }
+
+    pub fn seed(&self, fixture: &Path, src: &str) -> Result<(), Error> { // Adds the queries listed under src in a JSON fixture, all or nothing
+        let mut fixture: HashMap<String, Vec<AQuery>> = serde_json::from_str(&FileSystem::read_file(fixture)?)?;
+        self.add_batch(&fixture.remove(src).unwrap_or_default())
+    }
+
+    pub fn add_batch(&self, queries: &[AQuery]) -> Result<(), Error> { // All or nothing, statements are prepared once and reused across the batch
+        self.transaction(|database| queries.iter().try_for_each(|query| database.add(query)))
+    }
+
+    pub fn transaction<T, E: From<Error>>(&self, work: impl FnOnce(&Database) -> Result<T, E>) -> Result<T, E> { // Rolled back if work errors or panics, nests as a savepoint
+        self.pool.pin().map_err(Error::from)?; // Every call inside runs on this connection
+        let depth = self.pool.enter_transaction();
+        let savepoint = format!("nested_{}", depth);
+        let started = self.backend().map_err(Error::from).and_then(|conn| if depth == 1 { conn.begin() } else { conn.savepoint(&savepoint) });
+        if let Err(e) = started {
+            self.pool.leave_transaction();
+            self.pool.unpin();
+            return Err(e.into());
+        }
+
+        let result = match panic::catch_unwind(AssertUnwindSafe(|| work(self))) {
+            Ok(Ok(value)) => match self.finish(depth, &savepoint, true) {
+                Ok(()) => Ok(value),
+                Err(e) => {
+                    let _ = self.finish(depth, &savepoint, false);
+                    Err(e.into())
                 }
             },
-            Err(_) => {
-                if fail_safe {
-                    match TextFile::open(format!("{}.txt", this_db.src).as_str()) {
-                        Ok(path_buf) => Self { conn: DatabaseType::Textfile(path_buf) },
-                        Err(_) => Self { conn: DatabaseType::None },
-                    }
-                } else {
-                    Self { conn: DatabaseType::None }
+            Ok(Err(e)) => {
+                if let Err(rollback) = self.finish(depth, &savepoint, false) {
+                    println!("Failed to roll back: {}", rollback);
                 }
+                Err(e)
+            },
+            Err(cause) => {
+                let _ = self.finish(depth, &savepoint, false);
+                self.pool.leave_transaction();
+                self.pool.unpin();
+                panic::resume_unwind(cause);
             }
+        };
+        self.pool.leave_transaction();
+        self.pool.unpin();
+        result
+    }
+
+    pub fn lease(&self) -> Result<Lease<'_>, Error> { // Holds one connection for a unit of work, like a request, so it never waits midway
+        self.pool.pin()?;
+        Ok(Lease { pool: &self.pool })
+    }
+
+    fn finish(&self, depth: usize, savepoint: &str, keep: bool) -> Result<(), Error> {
+        let conn = self.backend()?;
+        match (depth, keep) {
+            (1, true) => conn.commit(),
+            (1, false) => conn.rollback(),
+            (_, true) => conn.release(savepoint),
+            (_, false) => conn.rollback_to(savepoint)
         }
     }
 
-    pub fn get<T>(&self, query: &GQuery) -> Result<Vec<Vec<T>>, Box<dyn Error>> 
-    where
-        T: FromSql + Send + 'static,
-    {
-        match &self.conn {
-            DatabaseType::Sqlite(conn) => {
-                match Sqlite::get::<T>(&conn, query) {
-                    Ok(values) => {
-                        Ok(values)
-                    },
-                    Err(e) => {
-                        println!("Failed2: {}", e);
-                        Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "Failed")))
+    pub fn add(&self, query: &AQuery) -> Result<(), Error> {
+        let (query, indexes) = self.fields.seal(query)?;
+        self.backend()?.add(&query, &indexes)
+    }
+
+    pub fn get<T: FromSql>(&self, query: &GQuery) -> Result<Vec<Vec<T>>, Error> {
+        self.retrieve(query).and_then(|rows| self.convert(rows))
+    }
+
+    pub fn fetch<T: FromRow>(&self, query: &GQuery) -> Result<Vec<T>, Error> { // Each row mapped to T, for rows mixing column types
+        let rows = self.retrieve(query)?;
+        let fetched: Result<Vec<T>, RowError> = rows.values
+            .iter()
+            .map(|values| T::from_row(&Row::new(&rows.columns, values)))
+            .collect();
+        Ok(fetched?)
+    }
+
+    pub fn first<T: FromRow>(&self, query: &GQuery) -> Result<Option<T>, Error> { // For lookups of at most one row
+        Ok(self.fetch(query)?.into_iter().next())
+    }
+
+    fn retrieve(&self, query: &GQuery) -> Result<Rows, Error> { // Runs the lookup and decrypts the encrypted columns
+        let query = self.fields.seal_lookup(query)?;
+        let rows = self.backend()?.get(&query, &self.fields)?;
+        self.decrypt(rows)
+    }
+
+    pub fn get_data<T: FromSql>(&self, sql: &str) -> Result<Vec<Vec<T>>, Error> {
+        let rows = self.backend()?.query(sql)?;
+        self.decrypt(rows).and_then(|rows| self.convert(rows))
+    }
+
+    pub fn remove(&self, table: &str, column: &str, value: &str) -> Result<(), Error> {
+        self.backend()?.remove(table, column, value)
+    }
+
+    pub fn wipe(&self) -> Result<(), Error> {
+        self.backend()?.wipe()
+    }
+
+    pub(crate) fn backend(&self) -> Result<Checkout<'_>, PoolError> { // Callers can downcast the error to PoolError to tell a busy pool from a failed query
+        self.pool.checkout()
+    }
+
+    fn decrypt(&self, mut rows: Rows) -> Result<Rows, Error> { // Values under a retired key are written back re-encrypted
+        let mut rewraps = Vec::new();
+        for row in rows.values.iter_mut() {
+            for (name, value) in rows.columns.iter().zip(row.iter_mut()) {
+                if let Value::Text(ciphertext) = value {
+                    if self.fields.encrypts(name) {
+                        let (plaintext, rewrap) = self.fields.open(name, ciphertext)?;
+                        rewraps.extend(rewrap);
+                        *ciphertext = plaintext;
                     }
                 }
-            },
-            _ => {
-                Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "Database type not implemented")))
             }
         }
+        for rewrap in &rewraps {
+            if let Err(e) = self.add(rewrap) { // Retried on the next read
+                println!("Failed to re-encrypt a field: {}", e);
+            }
+        }
+        Ok(rows)
     }
 
-    pub fn get_data<T>(&self, sql: &str) -> Result<Vec<Vec<T>>, Box<dyn Error>> 
-    where
-        T: FromSql + Send + 'static,
-    {
-        match &self.conn {
-            DatabaseType::Sqlite(conn) => {
-                match Sqlite::retrieve::<T>(&conn, sql, None) {
-                    Ok(values) => {
-                        Ok(values)
-                    }
-                    Err(e) => {
-                        println!("Failed {}", e);
-                        Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, e)))
-                    }
-                }
-            },
-            _ => {
-                println!("Failed");
-                Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "Failed")))
+    fn convert<T: FromSql>(&self, rows: Rows) -> Result<Vec<Vec<T>>, Error> { // Converts every value to T
+        let mut results = Vec::with_capacity(rows.values.len());
+        for row in rows.values {
+            let mut result_row = Vec::with_capacity(row.len());
+            for (index, value) in row.iter().enumerate() {
+                let value = T::column_result(ValueRef::from(value)).map_err(|e| RowError::Mistyped {
+                    column: rows.columns.get(index).cloned().unwrap_or_else(|| index.to_string()),
+                    error: e.to_string()
+                })?;
+                result_row.push(value);
             }
-        }   
+            results.push(result_row);
+        }
+        Ok(results)
+    }
+}
+
+impl std::fmt::Debug for Database {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Database")
+            .field("name", &self.name)
+            .field("pool", &self.pool)
+            .field("fields", &self.fields)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::schema::Schema;
+
+    fn event(kind: &str) -> AQuery {
+        AQuery::Event { timestamp: 0, kind: kind.to_string(), ip: None, username: None, detail: None }
     }
-}
\ No newline at end of file
+
+    fn kinds(database: &Database) -> Vec<String> {
+        database.get_data::<String>("SELECT kind FROM events ORDER BY id").unwrap().into_iter().flatten().collect()
+    }
+
+    #[test]
+    fn nested_transaction_rolls_back_to_its_savepoint() {
+        let database = Database::open(DatabaseType::Memory, Schema::logs(), false).unwrap();
+        database.transaction(|database| {
+            database.add(&event("outer"))?;
+            let inner: Result<(), Error> = database.transaction(|database| {
+                database.add(&event("inner"))?;
+                Err(Error::database("inner failed"))
+            });
+            assert!(inner.is_err());
+            database.add(&event("after"))
+        }).unwrap();
+        assert_eq!(kinds(&database), vec!["outer", "after"]);
+    }
+
+    #[test]
+    fn failed_transaction_keeps_nothing() {
+        let database = Database::open(DatabaseType::Memory, Schema::logs(), false).unwrap();
+        let result: Result<(), Error> = database.transaction(|database| {
+            database.add(&event("first"))?;
+            database.transaction(|database| database.add(&event("second")))?;
+            Err(Error::database("outer failed"))
+        });
+        assert!(result.is_err());
+        database.add_batch(&[event("a"), event("b")]).unwrap();
+        assert_eq!(kinds(&database), vec!["a", "b"]);
+
+        let panicked = panic::catch_unwind(AssertUnwindSafe(|| database.transaction(|database| -> Result<(), Error> {
+            database.add(&event("lost"))?;
+            panic!("mid transaction");
+        })));
+        assert!(panicked.is_err());
+        assert_eq!(kinds(&database), vec!["a", "b"]);
+    }
+}
@@ -0,0 +1,161 @@
+use std::sync::Arc;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::database::db::{AQuery, GQuery};
+use crate::login::encrypt::{CipherError, Encrypt, PLAINTEXT_MARK};
+use crate::login::keyring::Keyring;
+
+pub const KEY_NAME: &str = "fields";
+pub const INDEX_KEY_NAME: &str = "blind_index"; // Rotating it would orphan every stored index
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EncryptedColumn {
+    pub table: &'static str,
+    pub column: &'static str,
+    pub index: Option<&'static str> // Column holding an HMAC of the plaintext, so equality lookups still work
+}
+
+impl EncryptedColumn {
+    pub fn new(table: &'static str, column: &'static str) -> Self {
+        Self { table, column, index: None }
+    }
+
+    pub fn indexed(table: &'static str, column: &'static str, index: &'static str) -> Self {
+        Self { table, column, index: Some(index) }
+    }
+}
+
+pub struct IndexUpdate { // Fills in the blind index of the row that was just written, found by its unique ciphertext
+    pub table: &'static str,
+    pub column: &'static str,
+    pub index: &'static str,
+    pub ciphertext: String,
+    pub value: String
+}
+
+pub struct FieldCipher { // Encrypts declared columns on write and decrypts them on read
+    columns: Vec<EncryptedColumn>,
+    keyring: Option<Arc<Keyring>>
+}
+
+impl FieldCipher {
+    pub fn new(columns: Vec<EncryptedColumn>) -> Self {
+        Self { columns, keyring: None }
+    }
+
+    pub fn set_keyring(&mut self, keyring: Arc<Keyring>) {
+        self.keyring = Some(keyring);
+    }
+
+    pub fn columns(&self) -> &[EncryptedColumn] {
+        &self.columns
+    }
+
+    pub fn encrypts(&self, column: &str) -> bool { // Result rows only carry column names, so names are matched across tables
+        self.find(column).is_some()
+    }
+
+    pub fn lookup_column<'a>(&'a self, table: &str, column: &'a str) -> &'a str { // Where to filter on column, its blind index when encrypted
+        match self.columns.iter().find(|declared| declared.table == table && declared.column == column) {
+            Some(EncryptedColumn { index: Some(index), .. }) => index,
+            _ => column
+        }
+    }
+
+    pub fn seal(&self, query: &AQuery) -> Result<(AQuery, Vec<IndexUpdate>), CipherError> {
+        let mut sealed = query.clone();
+        let mut indexes = Vec::new();
+        for (table, column, value) in sealed.fields_mut() {
+            let declared = match self.columns.iter().find(|declared| declared.table == table && declared.column == column) {
+                Some(declared) => declared,
+                None => continue
+            };
+            let ciphertext = self.keyring()?.encrypt(KEY_NAME, value, Self::aad(table, column).as_bytes())?;
+            if let Some(index) = declared.index {
+                indexes.push(IndexUpdate {
+                    table,
+                    column,
+                    index,
+                    ciphertext: ciphertext.clone(),
+                    value: self.blind_index(table, column, value)?
+                });
+            }
+            *value = ciphertext;
+        }
+        Ok((sealed, indexes))
+    }
+
+    pub fn seal_lookup(&self, query: &GQuery) -> Result<GQuery, CipherError> { // Swaps an encrypted lookup value for its blind index
+        let mut sealed = query.clone();
+        if let Some((table, column, value)) = sealed.lookup_mut() {
+            match self.columns.iter().find(|declared| declared.table == table && declared.column == column) {
+                Some(EncryptedColumn { index: Some(_), .. }) => *value = self.blind_index(table, column, value)?,
+                Some(_) => return Err(CipherError::UnknownKey(INDEX_KEY_NAME.to_string())), // Encrypted but not searchable
+                None => {}
+            }
+        }
+        Ok(sealed)
+    }
+
+    pub fn open(&self, column: &str, ciphertext: &str) -> Result<(String, Option<AQuery>), CipherError> { // -> plaintext, and a rewrap when it used a retired key or none at all
+        let declared = self.find(column).ok_or_else(|| CipherError::UnknownKey(column.to_string()))?;
+        let aad = Self::aad(declared.table, declared.column);
+        if let Some(plaintext) = ciphertext.strip_prefix(PLAINTEXT_MARK) {
+            let rewrap = AQuery::FieldRewrap {
+                column: declared.clone(),
+                ciphertext: ciphertext.to_string(),
+                rotated: self.keyring()?.encrypt(KEY_NAME, plaintext, aad.as_bytes())?,
+                index: match declared.index {
+                    Some(_) => Some(self.blind_index(declared.table, declared.column, plaintext)?),
+                    None => None
+                }
+            };
+            return Ok((plaintext.to_string(), Some(rewrap)));
+        }
+
+        let decrypted = self.keyring()?.decrypt(ciphertext, aad.as_bytes())?;
+        let rewrap = decrypted.rotated.map(|rotated| AQuery::FieldRewrap {
+            column: declared.clone(),
+            ciphertext: ciphertext.to_string(),
+            rotated,
+            index: None
+        });
+        Ok((decrypted.plaintext, rewrap))
+    }
+
+    fn blind_index(&self, table: &str, column: &str, value: &str) -> Result<String, CipherError> {
+        let key = self.keyring()?
+            .key(INDEX_KEY_NAME)
+            .ok_or_else(|| CipherError::UnknownKey(INDEX_KEY_NAME.to_string()))?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(&*key).map_err(|_| CipherError::Failed)?;
+        mac.update(Self::aad(table, column).as_bytes());
+        mac.update(b":");
+        mac.update(value.as_bytes());
+        Ok(Encrypt::hex(&mac.finalize().into_bytes()))
+    }
+
+    fn find(&self, column: &str) -> Option<&EncryptedColumn> {
+        self.columns.iter().find(|declared| declared.column == column)
+    }
+
+    fn keyring(&self) -> Result<&Keyring, CipherError> {
+        self.keyring
+            .as_deref()
+            .ok_or_else(|| CipherError::UnknownKey(KEY_NAME.to_string()))
+    }
+
+    fn aad(table: &str, column: &str) -> String { // Stops a ciphertext being moved to another column
+        format!("{}.{}", table, column)
+    }
+}
+
+impl std::fmt::Debug for FieldCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("FieldCipher")
+            .field("columns", &self.columns)
+            .field("keyring", &self.keyring.is_some())
+            .finish()
+    }
+}
@@ -0,0 +1,187 @@
+use rusqlite::Connection;
+
+use crate::database::db::DatabaseStruct;
+use crate::database::sqlite::Sqlite;
+use crate::tools::utils::now;
+
+pub struct Migration { // Shipped migrations are never edited, schema changes go in a new one
+    pub version: i64,
+    pub name: &'static str,
+    pub statements: Vec<String>
+}
+
+impl Migration {
+    pub fn new(version: i64, name: &'static str, statements: &[&str]) -> Self {
+        Self {
+            version,
+            name,
+            statements: statements.iter().map(|statement| statement.to_string()).collect()
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum MigrationError {
+    OutOfOrder(i64), // Versions must start at 1 and go up by one
+    NewerDatabase {
+        database: i64,
+        code: i64
+    }, // Written by a newer build, refusing to touch it
+    Failed {
+        version: i64,
+        error: String
+    }, // Rolled back along with every other pending migration
+    DryRun(Vec<i64>) // Versions that would have been applied, nothing was kept
+}
+
+impl std::fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MigrationError::OutOfOrder(version) => write!(f, "migration {} is out of order", version),
+            MigrationError::NewerDatabase { database, code } => write!(f, "database is at version {} but this build only knows up to {}", database, code),
+            MigrationError::Failed { version, error } => write!(f, "migration {} failed: {}", version, error),
+            MigrationError::DryRun(versions) => write!(f, "dry run, would apply {:?}", versions)
+        }
+    }
+}
+
+impl std::error::Error for MigrationError {}
+
+pub struct Migrations; // Ordered schema changes, recorded in schema_migrations as they are applied
+
+impl Migrations {
+    pub fn version(conn: &Connection) -> rusqlite::Result<i64> {
+        conn.query_row("SELECT COALESCE(MAX(version), 0) FROM schema_migrations", [], |row| row.get(0))
+    }
+
+    pub fn run(conn: &Connection, this_db: &DatabaseStruct, dry_run: bool) -> Result<Vec<i64>, MigrationError> { // -> versions applied
+        let migrations = &this_db.migrations;
+        for (i, migration) in migrations.iter().enumerate() {
+            if migration.version != i as i64 + 1 {
+                return Err(MigrationError::OutOfOrder(migration.version));
+            }
+        }
+        let failed = |version: i64, error: rusqlite::Error| MigrationError::Failed { version, error: error.to_string() };
+
+        let transaction = conn.unchecked_transaction().map_err(|e| failed(0, e))?; // Everything pending lands together or not at all
+        let fresh = Self::fresh(&transaction, this_db).map_err(|e| failed(0, e))?;
+        transaction.execute(
+            "CREATE TABLE IF NOT EXISTS schema_migrations (version INTEGER PRIMARY KEY, name TEXT NOT NULL, applied INTEGER NOT NULL)",
+            []
+        ).map_err(|e| failed(0, e))?;
+        let current = Self::version(&transaction).map_err(|e| failed(0, e))?;
+        let latest = migrations.len() as i64;
+        if current > latest {
+            return Err(MigrationError::NewerDatabase { database: current, code: latest });
+        }
+
+        if fresh { // Built straight from the current tables, so every migration counts as applied
+            for statement in this_db.tables.iter().flat_map(|table| table.as_sql()) {
+                transaction.execute_batch(&statement).map_err(|e| failed(0, e))?;
+            }
+            if !dry_run { // Seeds can print one-off secrets, which would be lost with the rolled back rows
+                let seeds = (this_db.onload)().map_err(|e| MigrationError::Failed { version: 0, error: format!("onload seed failed: {}", e) })?;
+                for seed in &seeds {
+                    let (sql, params) = Sqlite::convert_a_to_sql(seed);
+                    transaction.execute(&sql, params.as_slice()).map_err(|e| failed(0, e))?;
+                }
+            }
+            println!("{} {} at schema version {}", if dry_run { "Would create" } else { "Creating" }, this_db.src, latest);
+        }
+
+        let mut applied = Vec::new();
+        for migration in migrations.iter().filter(|migration| migration.version > current) {
+            if !fresh {
+                for statement in &migration.statements {
+                    transaction.execute_batch(statement).map_err(|e| failed(migration.version, e))?;
+                }
+                println!("{} migration {}: {}", if dry_run { "Would apply" } else { "Applying" }, migration.version, migration.name);
+            }
+            transaction.execute(
+                "INSERT INTO schema_migrations (version, name, applied) VALUES (?1, ?2, ?3)",
+                (migration.version, migration.name, now() as i64)
+            ).map_err(|e| failed(migration.version, e))?;
+            applied.push(migration.version);
+        }
+
+        if dry_run {
+            return Err(MigrationError::DryRun(applied)); // Dropping the transaction rolls it back
+        }
+        transaction.commit().map_err(|e| failed(latest, e))?;
+        Ok(applied)
+    }
+
+    fn fresh(conn: &Connection, this_db: &DatabaseStruct) -> rusqlite::Result<bool> { // None of the tables exist yet
+        for table in &this_db.tables {
+            let count: i64 = conn.query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE type='table' AND name=?1",
+                [table.name],
+                |row| row.get(0)
+            )?;
+            if count > 0 {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::schema::Schema;
+
+    fn baseline() -> Connection { // The users table auto_reset created, with rows stored as-is
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("
+            CREATE TABLE users (username TEXT PRIMARY KEY, password TEXT, name TEXT, email TEXT, site TEXT);
+            INSERT INTO users VALUES ('bob', 'hunter2', 'Bob', 'bob@example.com', NULL);
+        ").unwrap();
+        conn
+    }
+
+    fn text(conn: &Connection, sql: &str) -> String {
+        conn.query_row(sql, [], |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn upgrades_baseline() {
+        let conn = baseline();
+        assert_eq!(Migrations::run(&conn, &Schema::logins(), false).unwrap(), vec![1]);
+        assert_eq!(Migrations::version(&conn).unwrap(), 1);
+        assert_eq!(text(&conn, "SELECT password FROM users WHERE username='bob'"), "plain:hunter2");
+        assert_eq!(text(&conn, "SELECT email FROM users WHERE username='bob'"), "plain:bob@example.com");
+        assert_eq!(text(&conn, "SELECT role FROM roles WHERE username='bob'"), "user");
+        assert_eq!(text(&conn, "SELECT COUNT(*) || '' FROM users"), "1"); // Not seeded, it isn't fresh
+
+        for table in Schema::logins().tables { // Every current column is there
+            for column in &table.columns {
+                conn.prepare(&format!("SELECT {} FROM {}", column.name, table.name)).unwrap();
+            }
+        }
+        assert_eq!(Migrations::run(&conn, &Schema::logins(), false).unwrap(), Vec::<i64>::new());
+    }
+
+    #[test]
+    fn dry_run_keeps_nothing() {
+        let conn = baseline();
+        assert!(matches!(Migrations::run(&conn, &Schema::logins(), true), Err(MigrationError::DryRun(versions)) if versions == vec![1]));
+        assert_eq!(text(&conn, "SELECT password FROM users WHERE username='bob'"), "hunter2");
+        assert_eq!(text(&conn, "SELECT COUNT(*) || '' FROM sqlite_master WHERE name IN ('schema_migrations', 'roles')"), "0");
+    }
+
+    #[test]
+    fn refuses_newer_database() {
+        let conn = baseline();
+        Migrations::run(&conn, &Schema::logins(), false).unwrap();
+        conn.execute("INSERT INTO schema_migrations (version, name, applied) VALUES (2, 'future', 0)", []).unwrap();
+        assert!(matches!(Migrations::run(&conn, &Schema::logins(), false), Err(MigrationError::NewerDatabase { database: 2, code: 1 })));
+    }
+
+    #[test]
+    fn refuses_gaps() {
+        let mut logins = Schema::logins();
+        logins.migrations.push(Migration::new(3, "skips 2", &[]));
+        assert!(matches!(Migrations::run(&baseline(), &logins, false), Err(MigrationError::OutOfOrder(3))));
+    }
+}
@@ -1,3 +1,10 @@
+pub mod access;
+pub mod backup;
 pub mod db;
+pub mod fields;
+pub mod migrations;
+pub mod pool;
+pub mod row;
+pub mod schema;
 pub mod sqlite;
 pub mod textfile;
\ No newline at end of file
@@ -0,0 +1,249 @@
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
+
+use crate::database::db::DatabaseConnection;
+
+pub type Connector = Box<dyn Fn() -> Option<Box<dyn DatabaseConnection>> + Send + Sync>; // Opens one more connection to the same database
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PoolError {
+    Timeout(Duration), // Every connection stayed checked out for this long
+    Closed // No backend, or another connection could not be opened
+}
+
+impl std::fmt::Display for PoolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PoolError::Timeout(waited) => write!(f, "no database connection free after {}ms", waited.as_millis()),
+            PoolError::Closed => write!(f, "no database connection could be opened")
+        }
+    }
+}
+
+impl std::error::Error for PoolError {}
+
+#[derive(Default)]
+struct Idle {
+    conns: Vec<Box<dyn DatabaseConnection>>,
+    open: usize // Idle plus checked out
+}
+
+struct Pinned { // A connection a thread keeps between calls, for transactions and request leases
+    conn: Option<Box<dyn DatabaseConnection>>, // None while a call is using it
+    depth: usize,
+    transactions: usize // Open on the connection, a lease alone pins without one
+}
+
+pub struct Pool { // Connections are opened on demand up to max_size and handed out one per call
+    name: &'static str,
+    idle: Mutex<Idle>,
+    returned: Condvar,
+    pinned: Mutex<HashMap<ThreadId, Pinned>>,
+    connect: Option<Connector>, // None keeps the pool at its first connection
+    max_size: usize,
+    timeout: Duration
+}
+
+impl Pool {
+    pub fn new(first: Box<dyn DatabaseConnection>, connect: Option<Connector>, max_size: usize, timeout: Duration) -> Self { // first is already initialised
+        let max_size = if connect.is_some() { max_size.max(1) } else { 1 };
+        Self {
+            name: first.name(),
+            idle: Mutex::new(Idle { conns: vec![first], open: 1 }),
+            returned: Condvar::new(),
+            pinned: Mutex::new(HashMap::new()),
+            connect,
+            max_size,
+            timeout
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn checkout(&self) -> Result<Checkout<'_>, PoolError> { // The thread's pinned connection if it has one, otherwise a free one
+        if let Some(conn) = self.lock_pinned().get_mut(&thread::current().id()).and_then(|pinned| pinned.conn.take()) {
+            return Ok(Checkout { pool: self, conn: Some(conn), pinned: true });
+        }
+        self.take().map(|conn| Checkout { pool: self, conn: Some(conn), pinned: false })
+    }
+
+    pub fn pin(&self) -> Result<(), PoolError> { // Keeps this thread on one connection until the matching unpin
+        let current = thread::current().id();
+        if let Some(pinned) = self.lock_pinned().get_mut(&current) {
+            pinned.depth += 1;
+            return Ok(());
+        }
+        let conn = self.take()?; // Not under the pinned lock, other threads' calls must go on while this one waits
+        self.lock_pinned().insert(current, Pinned { conn: Some(conn), depth: 1, transactions: 0 });
+        Ok(())
+    }
+
+    pub fn enter_transaction(&self) -> usize { // On this thread's pinned connection, -> nesting depth, 1 for the outermost
+        match self.lock_pinned().get_mut(&thread::current().id()) {
+            Some(pinned) => {
+                pinned.transactions += 1;
+                pinned.transactions
+            },
+            None => 0
+        }
+    }
+
+    pub fn leave_transaction(&self) {
+        if let Some(pinned) = self.lock_pinned().get_mut(&thread::current().id()) {
+            pinned.transactions = pinned.transactions.saturating_sub(1);
+        }
+    }
+
+    pub fn unpin(&self) {
+        let mut pinned = self.lock_pinned();
+        let current = thread::current().id();
+        let done = match pinned.get_mut(&current) {
+            Some(entry) => {
+                entry.depth -= 1;
+                entry.depth == 0
+            },
+            None => false
+        };
+        if done {
+            let conn = pinned.remove(&current).and_then(|entry| entry.conn);
+            drop(pinned);
+            if let Some(conn) = conn {
+                self.put(conn);
+            }
+        }
+    }
+
+    fn take(&self) -> Result<Box<dyn DatabaseConnection>, PoolError> {
+        let deadline = Instant::now() + self.timeout;
+        let mut idle = self.lock_idle();
+        loop {
+            if let Some(conn) = idle.conns.pop() {
+                return Ok(conn);
+            }
+            if let Some(connect) = self.connect.as_ref().filter(|_| idle.open < self.max_size) {
+                idle.open += 1;
+                drop(idle);
+                return match connect() {
+                    Some(conn) => Ok(conn),
+                    None => {
+                        self.lock_idle().open -= 1;
+                        Err(PoolError::Closed)
+                    }
+                };
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(PoolError::Timeout(self.timeout));
+            }
+            idle = match self.returned.wait_timeout(idle, remaining) {
+                Ok((idle, _)) => idle,
+                Err(poisoned) => poisoned.into_inner().0
+            };
+        }
+    }
+
+    fn put(&self, conn: Box<dyn DatabaseConnection>) {
+        self.lock_idle().conns.push(conn);
+        self.returned.notify_one();
+    }
+
+    fn lock_idle(&self) -> MutexGuard<'_, Idle> {
+        self.idle.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn lock_pinned(&self) -> MutexGuard<'_, HashMap<ThreadId, Pinned>> {
+        self.pinned.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl std::fmt::Debug for Pool {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let idle = self.lock_idle();
+        f.debug_struct("Pool")
+            .field("name", &self.name)
+            .field("open", &idle.open)
+            .field("idle", &idle.conns.len())
+            .field("max_size", &self.max_size)
+            .finish()
+    }
+}
+
+pub struct Checkout<'a> { // Goes back to the pool, or to the thread's pin, when dropped
+    pool: &'a Pool,
+    conn: Option<Box<dyn DatabaseConnection>>,
+    pinned: bool
+}
+
+impl Deref for Checkout<'_> {
+    type Target = dyn DatabaseConnection;
+
+    fn deref(&self) -> &Self::Target {
+        self.conn.as_deref().expect("connection is only taken on drop")
+    }
+}
+
+impl DerefMut for Checkout<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.conn.as_deref_mut().expect("connection is only taken on drop")
+    }
+}
+
+impl Drop for Checkout<'_> {
+    fn drop(&mut self) {
+        let conn = match self.conn.take() {
+            Some(conn) => conn,
+            None => return
+        };
+        if self.pinned {
+            if let Some(pinned) = self.pool.lock_pinned().get_mut(&thread::current().id()) {
+                pinned.conn = Some(conn);
+                return;
+            }
+        }
+        self.pool.put(conn);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::sqlite::Sqlite;
+    use crate::error::Error;
+
+    #[test]
+    fn checkout_times_out_as_unavailable() {
+        let pool = Pool::new(Box::new(Sqlite::memory().unwrap()), None, 4, Duration::from_millis(20));
+        let held = pool.checkout().unwrap();
+        let started = Instant::now();
+        let error = pool.checkout().err().unwrap();
+        assert_eq!(error, PoolError::Timeout(Duration::from_millis(20)));
+        assert!(started.elapsed() >= Duration::from_millis(20));
+        assert_eq!(Error::from(error).status(), (503, "Service Unavailable"));
+
+        drop(held);
+        assert!(pool.checkout().is_ok());
+    }
+
+    #[test]
+    fn pinned_thread_reuses_its_connection() {
+        let pool = Pool::new(Box::new(Sqlite::memory().unwrap()), None, 1, Duration::from_millis(20));
+        pool.pin().unwrap();
+        {
+            let _first = pool.checkout().unwrap();
+        }
+        assert!(pool.checkout().is_ok()); // Handed back to the pin, not the idle list
+        thread::scope(|scope| {
+            scope.spawn(|| assert!(matches!(pool.checkout().err(), Some(PoolError::Timeout(_)))));
+        });
+        pool.unpin();
+        thread::scope(|scope| {
+            scope.spawn(|| assert!(pool.checkout().is_ok()));
+        });
+    }
+}
@@ -0,0 +1,83 @@
+use rusqlite::types::{FromSql, Value, ValueRef};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RowError {
+    Missing(String), // No column by that name or position
+    Mistyped {
+        column: String,
+        error: String
+    }, // The value could not be read as the field's type
+    Failed(String) // The query itself failed, or a column could not be decrypted
+}
+
+impl std::fmt::Display for RowError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RowError::Missing(column) => write!(f, "missing column {}", column),
+            RowError::Mistyped { column, error } => write!(f, "column {}: {}", column, error),
+            RowError::Failed(error) => write!(f, "query failed: {}", error)
+        }
+    }
+}
+
+impl std::error::Error for RowError {}
+
+pub struct Row<'a> { // One decrypted result row, read by column name or position
+    columns: &'a [String],
+    values: &'a [Value]
+}
+
+impl<'a> Row<'a> {
+    pub fn new(columns: &'a [String], values: &'a [Value]) -> Self {
+        Self { columns, values }
+    }
+
+    pub fn get<T: FromSql>(&self, column: &str) -> Result<T, RowError> {
+        match self.columns.iter().position(|name| name == column) {
+            Some(index) => self.get_index(index),
+            None => Err(RowError::Missing(column.to_string()))
+        }
+    }
+
+    pub fn get_index<T: FromSql>(&self, index: usize) -> Result<T, RowError> {
+        let value = self.values.get(index).ok_or_else(|| RowError::Missing(index.to_string()))?;
+        T::column_result(ValueRef::from(value)).map_err(|e| RowError::Mistyped {
+            column: self.columns.get(index).cloned().unwrap_or_else(|| index.to_string()),
+            error: e.to_string()
+        })
+    }
+}
+
+pub trait FromRow: Sized {
+    fn from_row(row: &Row) -> Result<Self, RowError>;
+}
+
+#[macro_export]
+macro_rules! from_row { // Implements FromRow for a struct whose fields are named after the columns
+    ($type:ty { $($field:ident),* $(,)? }) => {
+        impl $crate::database::row::FromRow for $type {
+            fn from_row(row: &$crate::database::row::Row) -> Result<Self, $crate::database::row::RowError> {
+                Ok(Self {
+                    $($field: row.get(stringify!($field))?),*
+                })
+            }
+        }
+    };
+}
+
+macro_rules! tuple_from_row { // Tuples read their columns by position
+    ($($name:ident $index:tt),+) => {
+        impl<$($name: FromSql),+> FromRow for ($($name,)+) {
+            fn from_row(row: &Row) -> Result<Self, RowError> {
+                Ok(($(row.get_index::<$name>($index)?,)+))
+            }
+        }
+    };
+}
+
+tuple_from_row!(A 0);
+tuple_from_row!(A 0, B 1);
+tuple_from_row!(A 0, B 1, C 2);
+tuple_from_row!(A 0, B 1, C 2, D 3);
+tuple_from_row!(A 0, B 1, C 2, D 3, E 4);
+tuple_from_row!(A 0, B 1, C 2, D 3, E 4, F 5);
@@ -0,0 +1,166 @@
+use rand::Rng;
+use rand::distributions::Alphanumeric;
+
+use crate::database::db::{AQuery, DatabaseStruct, TableStruct, ColumnStruct};
+use crate::database::migrations::Migration;
+use crate::error::Error;
+use crate::login::access::ADMIN_ROLE;
+use crate::login::encrypt::Encrypt;
+use crate::login::register::Register;
+use crate::tools::config::DType;
+
+pub struct Schema; // Current tables and the migrations leading up to them, for each database the server opens
+
+impl Schema {
+    pub fn logins() -> DatabaseStruct<'static> {
+        let tables = vec![
+            TableStruct::new("users", vec![
+                ColumnStruct::new("username", DType::String),
+                ColumnStruct::new("password", DType::String),
+                ColumnStruct::new("name", DType::String).encrypted(None),
+                ColumnStruct::new("email", DType::String).encrypted(Some("email_index")),
+                ColumnStruct::new("email_index", DType::String).unique(),
+                ColumnStruct::new("site", DType::String),
+                ColumnStruct::new("locked", DType::Integer).not_null().default("0"),
+                ColumnStruct::new("locked_until", DType::Integer).not_null().default("0"),
+                ColumnStruct::new("disabled", DType::Integer).not_null().default("0"),
+                ColumnStruct::new("totp_secret", DType::String),
+                ColumnStruct::new("totp_enabled", DType::Integer).not_null().default("0"),
+                ColumnStruct::new("totp_last_step", DType::Integer).not_null().default("0"),
+                ColumnStruct::new("tokens_revoked", DType::Integer).not_null().default("0")
+            ]).primary_key(&["username"]),
+            TableStruct::new("roles", vec![
+                ColumnStruct::new("username", DType::String).not_null(),
+                ColumnStruct::new("role", DType::String).not_null()
+            ]).primary_key(&["username", "role"]),
+            TableStruct::new("sessions", vec![
+                ColumnStruct::new("token", DType::String),
+                ColumnStruct::new("username", DType::String).not_null(),
+                ColumnStruct::new("created", DType::Integer).not_null(),
+                ColumnStruct::new("expires", DType::Integer).not_null()
+            ]).primary_key(&["token"]).index("sessions_username", &["username"], false),
+            TableStruct::new("signing_keys", vec![
+                ColumnStruct::new("kid", DType::String),
+                ColumnStruct::new("key", DType::String).not_null(),
+                ColumnStruct::new("created", DType::Integer).not_null(),
+                ColumnStruct::new("retired", DType::Integer).not_null().default("0")
+            ]).primary_key(&["kid"]),
+            TableStruct::new("recovery_codes", vec![
+                ColumnStruct::new("username", DType::String).not_null(),
+                ColumnStruct::new("code", DType::String).not_null()
+            ]).primary_key(&["username", "code"]),
+            TableStruct::new("challenges", vec![
+                ColumnStruct::new("token", DType::String),
+                ColumnStruct::new("username", DType::String).not_null(),
+                ColumnStruct::new("grant", DType::String).not_null(),
+                ColumnStruct::new("expires", DType::Integer).not_null()
+            ]).primary_key(&["token"]),
+            TableStruct::new("password_resets", vec![
+                ColumnStruct::new("token", DType::String),
+                ColumnStruct::new("username", DType::String).not_null(),
+                ColumnStruct::new("expires", DType::Integer).not_null()
+            ]).primary_key(&["token"]),
+            TableStruct::new("api_keys", vec![
+                ColumnStruct::new("id", DType::String),
+                ColumnStruct::new("username", DType::String).not_null(),
+                ColumnStruct::new("label", DType::String),
+                ColumnStruct::new("hash", DType::String).not_null().unique(),
+                ColumnStruct::new("scopes", DType::String).not_null().default("''"),
+                ColumnStruct::new("created", DType::Integer).not_null(),
+                ColumnStruct::new("last_used", DType::Integer),
+                ColumnStruct::new("revoked", DType::Integer).not_null().default("0")
+            ]).primary_key(&["id"]).index("api_keys_username", &["username"], false)
+        ];
+        DatabaseStruct {
+            src: "logins",
+            migrations: vec![
+                Migration::new(1, "upgrade baseline", &[ // The baseline only had users (username, password, name, email, site), all stored as-is
+                    "ALTER TABLE users ADD COLUMN email_index TEXT",
+                    "ALTER TABLE users ADD COLUMN locked INTEGER NOT NULL DEFAULT 0",
+                    "ALTER TABLE users ADD COLUMN locked_until INTEGER NOT NULL DEFAULT 0",
+                    "ALTER TABLE users ADD COLUMN disabled INTEGER NOT NULL DEFAULT 0",
+                    "ALTER TABLE users ADD COLUMN totp_secret TEXT",
+                    "ALTER TABLE users ADD COLUMN totp_enabled INTEGER NOT NULL DEFAULT 0",
+                    "ALTER TABLE users ADD COLUMN totp_last_step INTEGER NOT NULL DEFAULT 0",
+                    "ALTER TABLE users ADD COLUMN tokens_revoked INTEGER NOT NULL DEFAULT 0",
+                    "CREATE UNIQUE INDEX IF NOT EXISTS users_email_index ON users (email_index)",
+                    "UPDATE users SET password = 'plain:' || password WHERE password IS NOT NULL", // Hashed on the next sign in
+                    "UPDATE users SET name = 'plain:' || name WHERE name IS NOT NULL", // Encrypted when the database is next opened with its keyring
+                    "UPDATE users SET email = 'plain:' || email WHERE email IS NOT NULL",
+                    "CREATE TABLE IF NOT EXISTS roles (username TEXT NOT NULL, role TEXT NOT NULL, PRIMARY KEY (username, role))",
+                    "INSERT OR IGNORE INTO roles (username, role) SELECT username, 'user' FROM users",
+                    "CREATE TABLE IF NOT EXISTS sessions (token TEXT, username TEXT NOT NULL, created INTEGER NOT NULL, expires INTEGER NOT NULL, PRIMARY KEY (token))",
+                    "CREATE INDEX IF NOT EXISTS sessions_username ON sessions (username)",
+                    "CREATE TABLE IF NOT EXISTS signing_keys (kid TEXT, key TEXT NOT NULL, created INTEGER NOT NULL, retired INTEGER NOT NULL DEFAULT 0, PRIMARY KEY (kid))",
+                    "CREATE TABLE IF NOT EXISTS recovery_codes (username TEXT NOT NULL, code TEXT NOT NULL, PRIMARY KEY (username, code))",
+                    "CREATE TABLE IF NOT EXISTS challenges (token TEXT, username TEXT NOT NULL, grant TEXT NOT NULL, expires INTEGER NOT NULL, PRIMARY KEY (token))",
+                    "CREATE TABLE IF NOT EXISTS password_resets (token TEXT, username TEXT NOT NULL, expires INTEGER NOT NULL, PRIMARY KEY (token))",
+                    "CREATE TABLE IF NOT EXISTS api_keys (id TEXT, username TEXT NOT NULL, label TEXT, hash TEXT NOT NULL UNIQUE, scopes TEXT NOT NULL DEFAULT '', created INTEGER NOT NULL, last_used INTEGER, revoked INTEGER NOT NULL DEFAULT 0, PRIMARY KEY (id))",
+                    "CREATE INDEX IF NOT EXISTS api_keys_username ON api_keys (username)"
+                ])
+            ],
+            tables,
+            onload: Self::admin
+        }
+    }
+
+    fn admin() -> Result<Vec<AQuery>, Error> { // First admin account, with a random password shown only this once
+        let password = loop {
+            let password: String = rand::thread_rng().sample_iter(&Alphanumeric).take(20).map(char::from).collect();
+            if Register::validate_password("admin", &password).is_ok() {
+                break password;
+            }
+        };
+        let seeds = vec![
+            AQuery::User {
+                username: String::from("admin"),
+                password: Encrypt::password(&password)?,
+                name: None,
+                email: None,
+                site: None
+            },
+            AQuery::Role {
+                username: String::from("admin"),
+                role: ADMIN_ROLE.to_string()
+            }
+        ];
+        println!("Created account admin with password {}, change it after signing in", password);
+        Ok(seeds)
+    }
+
+    pub fn logs() -> DatabaseStruct<'static> {
+        let tables = vec![
+            TableStruct::new("events", vec![
+                ColumnStruct::new("id", DType::Integer).auto_increment(),
+                ColumnStruct::new("timestamp", DType::Integer).not_null(),
+                ColumnStruct::new("kind", DType::String).not_null(),
+                ColumnStruct::new("ip", DType::String),
+                ColumnStruct::new("username", DType::String),
+                ColumnStruct::new("detail", DType::String)
+            ]).index("events_timestamp", &["timestamp"], false),
+            TableStruct::new("access_log", vec![
+                ColumnStruct::new("id", DType::Integer).auto_increment(),
+                ColumnStruct::new("timestamp", DType::Integer).not_null(),
+                ColumnStruct::new("ip", DType::String),
+                ColumnStruct::new("method", DType::String).not_null(),
+                ColumnStruct::new("path", DType::String).not_null(),
+                ColumnStruct::new("status", DType::Integer).not_null(),
+                ColumnStruct::new("bytes", DType::Integer).not_null().default("0"),
+                ColumnStruct::new("duration_ms", DType::Integer).not_null().default("0"),
+                ColumnStruct::new("username", DType::String),
+                ColumnStruct::new("user_agent", DType::String)
+            ]).index("access_log_timestamp", &["timestamp"], false)
+        ];
+        DatabaseStruct {
+            src: "logs",
+            migrations: vec![
+                Migration::new(1, "access log", &[
+                    "CREATE TABLE IF NOT EXISTS access_log (id INTEGER PRIMARY KEY AUTOINCREMENT, timestamp INTEGER NOT NULL, ip TEXT, method TEXT NOT NULL, path TEXT NOT NULL, status INTEGER NOT NULL, bytes INTEGER NOT NULL DEFAULT 0, duration_ms INTEGER NOT NULL DEFAULT 0, username TEXT, user_agent TEXT)",
+                    "CREATE INDEX IF NOT EXISTS access_log_timestamp ON access_log (timestamp)"
+                ])
+            ],
+            tables,
+            onload: || Ok(vec![])
+        }
+    }
+}
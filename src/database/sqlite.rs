@@ -1,130 +1,85 @@
-use rusqlite::ffi::sqlite3_index_info_sqlite3_index_constraint_usage;
-use rusqlite::{Connection, Result, Error};
-use rusqlite::types::FromSql;
-use serde_json::Value;
+use std::path::Path;
+use std::time::Duration;
+
+use rusqlite::{Connection, OpenFlags};
+use rusqlite::backup::Backup;
+use rusqlite::types::Value;
 use crate::tools::filesystem::FileSystem;
-use crate::database::db::{AQuery, GQuery};
-use crate::DatabaseID;
-use crate::tools::config::{get_config};
+use crate::database::access::AccessFilter;
+use crate::database::backup::BackupError;
+use crate::database::db::{AQuery, GQuery, DatabaseConnection, Rows};
+use crate::database::fields::{FieldCipher, IndexUpdate};
+use crate::database::migrations::{Migrations, MigrationError};
+use crate::error::Error;
+use crate::login::encrypt::PLAINTEXT_MARK;
+use crate::tools::config::{get_bool, get_integer};
 
 use super::db::DatabaseStruct;
 
-pub struct Sqlite;
+const STATEMENT_CACHE: usize = 64; // Prepared statements kept per connection, enough for every query this crate issues often
+
+const BACKUP_PAGES: std::os::raw::c_int = 256; // Copied per step, writers get the lock back between steps
+const BACKUP_PAUSE: Duration = Duration::from_millis(10);
+
+const ACCESS_FILTER: &str = "(?1 IS NULL OR timestamp >= ?1) AND (?2 IS NULL OR timestamp < ?2) AND (?3 IS NULL OR status / 100 = ?3) \
+    AND (?4 IS NULL OR substr(path, 1, length(?4)) = ?4) AND (?5 IS NULL OR ip = ?5) AND (?6 IS NULL OR username = ?6)"; // Unset filters bind NULL and match everything
+
+pub struct Sqlite {
+    conn: Connection,
+    name: &'static str
+}
 
 impl Sqlite {
-    pub fn open(path: &str) -> Result<Connection, Error> {
+    pub fn open(path: &str) -> rusqlite::Result<Self> {
         match FileSystem::check_file_availability(path.to_string(), "db".to_string()) {
             Some(path_buf) => {
-                Connection::open(path_buf)
+                let conn = Connection::open(path_buf)?;
+                conn.busy_timeout(Duration::from_millis(get_integer("database_busy_timeout_ms")))?; // Waits on other connections' locks instead of failing
+                conn.query_row("PRAGMA journal_mode=WAL", [], |row| row.get::<_, String>(0))?; // Readers don't block the writer or each other
+                conn.execute_batch("PRAGMA synchronous=NORMAL")?;
+                Ok(Self::wrap(conn, "sqlite"))
             },
-            None => Err(Error::ExecuteReturnedResults)
+            None => Err(rusqlite::Error::ExecuteReturnedResults)
         }
 
     }
 
-    pub fn init(conn: &Connection, this_db: DatabaseStruct) -> bool {
-        let table_names: Result<Vec<String>> = conn
-        .prepare("SELECT name FROM sqlite_master WHERE type='table'")
-        .and_then(|mut stmt| {
-            stmt.query_map([], |row| row.get(0))
-                .and_then(|mapped| mapped.collect())
-        });
-        let auto_reset_state = get_config("auto_reset")
-            .unwrap_or(Value::Bool(false))
-            .as_bool()
-            .unwrap_or(false);
-
-        let mut status = false;
-        if auto_reset_state {
-            status = match table_names {
-                Ok(names) => {
-                    for table_name in names {
-                        if table_name != "sqlite_sequence" {
-                            if let Err(_) = conn.execute(&format!("DROP TABLE IF EXISTS {}", table_name), []) {
-                                return false; 
-                            }
-                        }
-                    }
-                    true
-                }
-                Err(_) => false, 
-            };
-        }
-
-        if status && auto_reset_state {
-            let sql_string = this_db.items
-                .iter()
-                .map(|(str, dtype)| {format!("{} {}", str, dtype.as_sql())})
-                .collect::<Vec<String>>()
-                .join(", ");
-
-            if Self::execute(
-                &conn, 
-                "CREATE TABLE IF NOT EXISTS users (
-                    username TEXT PRIMARY KEY,
-                    password TEXT,
-                    name TEXT,
-                    email TEXT,
-                    site TEXT 
-                )",
-                []
-            ) {
-                return Self::execute(
-                    &conn,
-                    "INSERT INTO users (username, password, name) VALUES (?1, ?2, ?3)",
-                    &["admin", "admin123", "Admin"],
-                )
-            }
-        }
-        false
+    pub fn memory() -> rusqlite::Result<Self> { // Nothing touches the disk, gone when dropped
+        Connection::open_in_memory().map(|conn| Self::wrap(conn, "memory"))
     }
 
-    pub fn get<T>(conn: &Connection, query: &GQuery) -> Result<Vec<Vec<T>>>
-    where
-        T: FromSql + Send + 'static,
-    {
-        let (sql, params) = Self::convertGToSql(query);
-        Self::retrieve::<T>(conn, sql.as_str(), Some(params))
+    fn wrap(conn: Connection, name: &'static str) -> Self {
+        conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE);
+        Self { conn, name }
     }
-    
-    pub fn execute<P: rusqlite::Params>(conn: &Connection, sql: &str, params: P) -> bool {
-        let execution_result = conn.execute(
-            sql,
-            params
-        );
 
-        if execution_result.is_err() {
-            return false;
-        }
-        true
+    pub fn execute<P: rusqlite::Params>(conn: &Connection, sql: &str, params: P) -> rusqlite::Result<()> {
+        conn.prepare_cached(sql)?.execute(params)?; // Repeated statements, like a batch of inserts, skip re-parsing
+        Ok(())
     }
 
-    pub fn retrieve<T>(conn: &Connection, sql: &str, params: Option<Vec<&dyn rusqlite::ToSql>>) -> Result<Vec<Vec<T>>>
-    where
-        T: FromSql + Send + 'static,
-    {
-        let mut stmt = conn.prepare(sql)?;
+    pub fn retrieve(conn: &Connection, sql: &str, params: Option<Vec<&dyn rusqlite::ToSql>>) -> rusqlite::Result<Rows> {
+        let mut stmt = conn.prepare_cached(sql)?;
+        let columns: Vec<String> = stmt.column_names().iter().map(|name| name.to_string()).collect();
 
         let rows = stmt.query_map(params.as_deref().unwrap_or(&[]), |row| {
-            let column_count = row.as_ref().column_names().len();
-            let mut result_row = Vec::with_capacity(column_count); 
+            let mut result_row = Vec::with_capacity(columns.len()); 
 
-            for i in 0..column_count {
-                result_row.push(row.get(i)?); 
+            for i in 0..columns.len() {
+                result_row.push(row.get::<_, Value>(i)?); 
             }
             Ok(result_row)
         })?;
 
-        let mut results = Vec::new(); 
+        let mut values = Vec::new(); 
         for row in rows {
-            let row_data: Vec<T> = row?;
-            results.push(row_data); 
+            values.push(row?); 
         }
 
-        Ok(results) 
+        Ok(Rows { columns, values }) 
     }
 
-    fn convertAToSql(query: &AQuery) -> (String, Vec<&dyn rusqlite::ToSql>) {
+    pub fn convert_a_to_sql(query: &AQuery) -> (String, Vec<&dyn rusqlite::ToSql>) {
         match query {
             AQuery::User { 
                 username, 
@@ -134,7 +89,7 @@ impl Sqlite {
                 site 
             } => {
                 (String::from(
-                    "INSERT INTO users (userame, password, name, email, site) VALUES (?1, ?2, ?3, ?4, ?5)"), 
+                    "INSERT INTO users (username, password, name, email, site) VALUES (?1, ?2, ?3, ?4, ?5)"), 
                     vec![username, password, name, email, site]
                 )
             },
@@ -156,18 +111,541 @@ impl Sqlite {
                     "UPDATE users SET site=?1 WHERE username=?2"), 
                     vec![site, username]
                 )
+            },
+            AQuery::Password { 
+                username, 
+                password 
+            } => {
+                (String::from(
+                    "UPDATE users SET password=?1 WHERE username=?2"), 
+                    vec![password, username]
+                )
+            },
+            AQuery::Lock { 
+                username, 
+                until 
+            } => {
+                (String::from(
+                    "UPDATE users SET locked_until=?1 WHERE username=?2"), 
+                    vec![until, username]
+                )
+            },
+            AQuery::Unlock { 
+                username 
+            } => {
+                (String::from(
+                    "UPDATE users SET locked=0, locked_until=0 WHERE username=?1"), 
+                    vec![username]
+                )
+            },
+            AQuery::Event { 
+                timestamp, 
+                kind, 
+                ip, 
+                username, 
+                detail 
+            } => {
+                (String::from(
+                    "INSERT INTO events (timestamp, kind, ip, username, detail) VALUES (?1, ?2, ?3, ?4, ?5)"), 
+                    vec![timestamp, kind, ip, username, detail]
+                )
+            },
+            AQuery::Session { 
+                token, 
+                username, 
+                created, 
+                expires 
+            } => {
+                (String::from(
+                    "INSERT INTO sessions (token, username, created, expires) VALUES (?1, ?2, ?3, ?4)"), 
+                    vec![token, username, created, expires]
+                )
+            },
+            AQuery::EndSession { 
+                token 
+            } => {
+                (String::from(
+                    "DELETE FROM sessions WHERE token=?1"), 
+                    vec![token]
+                )
+            },
+            AQuery::Role { 
+                username, 
+                role 
+            } => {
+                (String::from(
+                    "INSERT OR IGNORE INTO roles (username, role) VALUES (?1, ?2)"), 
+                    vec![username, role]
+                )
+            },
+            AQuery::RevokeRole { 
+                username, 
+                role 
+            } => {
+                (String::from(
+                    "DELETE FROM roles WHERE username=?1 AND role=?2"), 
+                    vec![username, role]
+                )
+            },
+            AQuery::SigningKey { 
+                kid, 
+                key, 
+                created 
+            } => {
+                (String::from(
+                    "INSERT INTO signing_keys (kid, key, created) VALUES (?1, ?2, ?3)"), 
+                    vec![kid, key, created]
+                )
+            },
+            AQuery::SigningKeyRewrap { 
+                kid, 
+                key 
+            } => {
+                (String::from(
+                    "UPDATE signing_keys SET key=?1 WHERE kid=?2"), 
+                    vec![key, kid]
+                )
+            },
+            AQuery::RetireSigningKeys { 
+                retired 
+            } => {
+                (String::from(
+                    "UPDATE signing_keys SET retired=?1 WHERE retired=0"), 
+                    vec![retired]
+                )
+            },
+            AQuery::TotpSecret { 
+                username, 
+                secret 
+            } => {
+                (String::from(
+                    "UPDATE users SET totp_secret=?1, totp_enabled=0, totp_last_step=0 WHERE username=?2"), 
+                    vec![secret, username]
+                )
+            },
+            AQuery::TotpRewrap { 
+                username, 
+                secret 
+            } => {
+                (String::from(
+                    "UPDATE users SET totp_secret=?1 WHERE username=?2"), 
+                    vec![secret, username]
+                )
+            },
+            AQuery::TotpEnable { 
+                username 
+            } => {
+                (String::from(
+                    "UPDATE users SET totp_enabled=1 WHERE username=?1 AND totp_secret IS NOT NULL"), 
+                    vec![username]
+                )
+            },
+            AQuery::TotpStep { 
+                username, 
+                step 
+            } => {
+                (String::from(
+                    "UPDATE users SET totp_last_step=?1 WHERE username=?2"), 
+                    vec![step, username]
+                )
+            },
+            AQuery::TotpDisable { 
+                username 
+            } => {
+                (String::from(
+                    "UPDATE users SET totp_secret=NULL, totp_enabled=0, totp_last_step=0 WHERE username=?1"), 
+                    vec![username]
+                )
+            },
+            AQuery::RecoveryCode { 
+                username, 
+                code 
+            } => {
+                (String::from(
+                    "INSERT INTO recovery_codes (username, code) VALUES (?1, ?2)"), 
+                    vec![username, code]
+                )
+            },
+            AQuery::UseRecoveryCode { 
+                username, 
+                code 
+            } => {
+                (String::from(
+                    "DELETE FROM recovery_codes WHERE username=?1 AND code=?2"), 
+                    vec![username, code]
+                )
+            },
+            AQuery::ClearRecoveryCodes { 
+                username 
+            } => {
+                (String::from(
+                    "DELETE FROM recovery_codes WHERE username=?1"), 
+                    vec![username]
+                )
+            },
+            AQuery::Challenge { 
+                token, 
+                username, 
+                grant, 
+                expires 
+            } => {
+                (String::from(
+                    "INSERT INTO challenges (token, username, grant, expires) VALUES (?1, ?2, ?3, ?4)"), 
+                    vec![token, username, grant, expires]
+                )
+            },
+            AQuery::EndChallenge { 
+                token 
+            } => {
+                (String::from(
+                    "DELETE FROM challenges WHERE token=?1"), 
+                    vec![token]
+                )
+            },
+            AQuery::EndSessions { 
+                username 
+            } => {
+                (String::from(
+                    "DELETE FROM sessions WHERE username=?1"), 
+                    vec![username]
+                )
+            },
+            AQuery::Reset { 
+                token, 
+                username, 
+                expires 
+            } => {
+                (String::from(
+                    "INSERT INTO password_resets (token, username, expires) VALUES (?1, ?2, ?3)"), 
+                    vec![token, username, expires]
+                )
+            },
+            AQuery::EndResets { 
+                username 
+            } => {
+                (String::from(
+                    "DELETE FROM password_resets WHERE username=?1"), 
+                    vec![username]
+                )
+            },
+            AQuery::ApiKey { 
+                id, 
+                username, 
+                label, 
+                hash, 
+                scopes, 
+                created 
+            } => {
+                (String::from(
+                    "INSERT INTO api_keys (id, username, label, hash, scopes, created) VALUES (?1, ?2, ?3, ?4, ?5, ?6)"), 
+                    vec![id, username, label, hash, scopes, created]
+                )
+            },
+            AQuery::RevokeApiKey { 
+                id, 
+                username 
+            } => {
+                (String::from(
+                    "UPDATE api_keys SET revoked=1 WHERE id=?1 AND username=?2"), 
+                    vec![id, username]
+                )
+            },
+            AQuery::ApiKeyUsed { 
+                hash, 
+                used 
+            } => {
+                (String::from(
+                    "UPDATE api_keys SET last_used=?1 WHERE hash=?2"), 
+                    vec![used, hash]
+                )
+            },
+            AQuery::Profile { 
+                username, 
+                name, 
+                email, 
+                site 
+            } => {
+                (String::from(
+                    "UPDATE users SET name=COALESCE(?1, name), email=COALESCE(?2, email), site=COALESCE(?3, site) WHERE username=?4"), 
+                    vec![name, email, site, username]
+                )
+            },
+            AQuery::DeleteUser { 
+                username 
+            } => {
+                (String::from(
+                    "DELETE FROM users WHERE username=?1"), 
+                    vec![username]
+                )
+            },
+            AQuery::RevokeRoles { 
+                username 
+            } => {
+                (String::from(
+                    "DELETE FROM roles WHERE username=?1"), 
+                    vec![username]
+                )
+            },
+            AQuery::DeleteApiKeys { 
+                username 
+            } => {
+                (String::from(
+                    "DELETE FROM api_keys WHERE username=?1"), 
+                    vec![username]
+                )
+            },
+            AQuery::FieldRewrap { 
+                column, 
+                ciphertext, 
+                rotated, 
+                index 
+            } => match column.index { // Names come from the schema, ciphertexts are unique
+                Some(index_column) => (format!(
+                    "UPDATE {} SET {}=?1, {}=COALESCE(?3, {}) WHERE {}=?2", column.table, column.column, index_column, index_column, column.column), 
+                    vec![rotated, ciphertext, index]
+                ),
+                None => (format!(
+                    "UPDATE {} SET {}=?1 WHERE {}=?2", column.table, column.column, column.column), 
+                    vec![rotated, ciphertext]
+                )
+            },
+            AQuery::RevokeApiKeys { 
+                username 
+            } => {
+                (String::from(
+                    "UPDATE api_keys SET revoked=1 WHERE username=?1"), 
+                    vec![username]
+                )
+            },
+            AQuery::RevokeTokens { 
+                username, 
+                before 
+            } => {
+                (String::from(
+                    "UPDATE users SET tokens_revoked=?1 WHERE username=?2"), 
+                    vec![before, username]
+                )
+            },
+            AQuery::Access { 
+                timestamp, 
+                ip, 
+                method, 
+                path, 
+                status, 
+                bytes, 
+                duration_ms, 
+                username, 
+                user_agent 
+            } => {
+                (String::from(
+                    "INSERT INTO access_log (timestamp, ip, method, path, status, bytes, duration_ms, username, user_agent) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)"), 
+                    vec![timestamp, ip, method, path, status, bytes, duration_ms, username, user_agent]
+                )
+            },
+            AQuery::PruneAccess { 
+                before 
+            } => {
+                (String::from(
+                    "DELETE FROM access_log WHERE timestamp < ?1"), 
+                    vec![before]
+                )
             }
         }
     }
 
-    fn convertGToSql(query: &GQuery) -> (String, Vec<&dyn rusqlite::ToSql>) {
+    fn convert_g_to_sql<'a>(query: &'a GQuery, fields: &FieldCipher) -> (String, Vec<&'a dyn rusqlite::ToSql>) {
         match query {
             GQuery::Password { username } => {
                 (String::from("Select password From users WHERE username = ?1"), vec![username as &dyn rusqlite::ToSql])
             },
             GQuery::UserData { username } => {
                 (String::from("Select name,email From users WHERE username = ?1"), vec![username as &dyn rusqlite::ToSql])
+            },
+            GQuery::Email { email } => {
+                (format!("Select username From users WHERE {} = ?1", fields.lookup_column("users", "email")), vec![email as &dyn rusqlite::ToSql])
+            },
+            GQuery::User { username } => {
+                (String::from("Select username,name,email,site From users WHERE username = ?1"), vec![username as &dyn rusqlite::ToSql])
+            },
+            GQuery::Status { username } => {
+                (String::from("Select locked,disabled,locked_until From users WHERE username = ?1"), vec![username as &dyn rusqlite::ToSql])
+            },
+            GQuery::Session { token, now } => {
+                (String::from("Select username From sessions WHERE token = ?1 AND expires > ?2"), vec![token as &dyn rusqlite::ToSql, now])
+            },
+            GQuery::Roles { username } => {
+                (String::from("Select role From roles WHERE username = ?1"), vec![username as &dyn rusqlite::ToSql])
+            },
+            GQuery::ActiveSigningKey => {
+                (String::from("Select kid,key From signing_keys WHERE retired = 0 ORDER BY created DESC LIMIT 1"), vec![])
+            },
+            GQuery::SigningKey { kid, since } => {
+                (String::from("Select key From signing_keys WHERE kid = ?1 AND (retired = 0 OR retired > ?2)"), vec![kid as &dyn rusqlite::ToSql, since])
+            },
+            GQuery::TotpSecret { username } => {
+                (String::from("Select totp_secret From users WHERE username = ?1"), vec![username as &dyn rusqlite::ToSql])
+            },
+            GQuery::TotpState { username } => {
+                (String::from("Select totp_enabled,totp_last_step From users WHERE username = ?1"), vec![username as &dyn rusqlite::ToSql])
+            },
+            GQuery::RecoveryCode { username, code } => {
+                (String::from("Select code From recovery_codes WHERE username = ?1 AND code = ?2"), vec![username as &dyn rusqlite::ToSql, code])
+            },
+            GQuery::Challenge { token, now } => {
+                (String::from("Select username,grant From challenges WHERE token = ?1 AND expires > ?2"), vec![token as &dyn rusqlite::ToSql, now])
+            },
+            GQuery::Reset { token, now } => {
+                (String::from("Select username From password_resets WHERE token = ?1 AND expires > ?2"), vec![token as &dyn rusqlite::ToSql, now])
+            },
+            GQuery::ApiKey { hash } => {
+                (String::from("Select username,scopes From api_keys WHERE hash = ?1 AND revoked = 0"), vec![hash as &dyn rusqlite::ToSql])
+            },
+            GQuery::ApiKeys { username } => {
+                (String::from("Select id,label,scopes,created,last_used,revoked From api_keys WHERE username = ?1 ORDER BY created"), vec![username as &dyn rusqlite::ToSql])
+            },
+            GQuery::Users { order, descending, limit, offset } => { // The order column comes from a fixed list, never from the caller
+                (format!("Select username,name,email,site From users ORDER BY {} {} LIMIT ?1 OFFSET ?2", order.column(), if *descending { "DESC" } else { "ASC" }), vec![limit as &dyn rusqlite::ToSql, offset])
+            },
+            GQuery::UserCount => {
+                (String::from("Select COUNT(*) AS count From users"), vec![])
+            },
+            GQuery::UserExists { username } => {
+                (String::from("Select EXISTS(Select 1 From users WHERE username = ?1) AS \"exists\""), vec![username as &dyn rusqlite::ToSql])
+            },
+            GQuery::TokensRevoked { username } => {
+                (String::from("Select tokens_revoked From users WHERE username = ?1"), vec![username as &dyn rusqlite::ToSql])
+            },
+            GQuery::PlaintextFields { column } => {
+                (format!("Select {} From {} WHERE substr({}, 1, {}) = '{}'", column.column, column.table, column.column, PLAINTEXT_MARK.len(), PLAINTEXT_MARK), vec![])
+            },
+            GQuery::AccessEntries { filter, limit, offset } => {
+                let mut params = Self::access_params(filter);
+                params.extend([limit as &dyn rusqlite::ToSql, offset]);
+                (format!("Select timestamp,ip,method,path,status,bytes,duration_ms,username,user_agent From access_log WHERE {} ORDER BY timestamp DESC, id DESC LIMIT ?7 OFFSET ?8", ACCESS_FILTER), params)
+            },
+            GQuery::AccessPerMinute { filter } => {
+                (format!("Select (timestamp / 60) * 60 AS minute, COUNT(*) AS requests, SUM(status >= 400 AND status < 500) AS client_errors, SUM(status >= 500) AS server_errors From access_log WHERE {} GROUP BY minute ORDER BY minute", ACCESS_FILTER), Self::access_params(filter))
+            },
+            GQuery::AccessTopPaths { filter, limit } => {
+                let mut params = Self::access_params(filter);
+                params.push(limit);
+                (format!("Select path, COUNT(*) AS requests, SUM(status >= 400 AND status < 500) AS client_errors, SUM(status >= 500) AS server_errors From access_log WHERE {} GROUP BY path ORDER BY requests DESC, path LIMIT ?7", ACCESS_FILTER), params)
+            },
+            GQuery::AccessSummary { filter } => {
+                (format!("Select COUNT(*) AS requests, COALESCE(SUM(status >= 400 AND status < 500), 0) AS client_errors, COALESCE(SUM(status >= 500), 0) AS server_errors, COALESCE(SUM(duration_ms), 0) AS duration_ms, COALESCE(SUM(bytes), 0) AS bytes From access_log WHERE {}", ACCESS_FILTER), Self::access_params(filter))
+            }
+        }
+    }
+
+    fn access_params(filter: &AccessFilter) -> Vec<&dyn rusqlite::ToSql> { // ?1 to ?6 of ACCESS_FILTER
+        vec![&filter.since, &filter.until, &filter.status_class, &filter.path_prefix, &filter.ip, &filter.username]
+    }
+}
+
+impl DatabaseConnection for Sqlite {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn init(&self, this_db: &DatabaseStruct) -> Result<(), MigrationError> {
+        Migrations::run(&self.conn, this_db, get_bool("migrations_dry_run")).map(|_| ())
+    }
+
+    fn add(&self, query: &AQuery, indexes: &[IndexUpdate]) -> Result<(), Error> {
+        let (sql, params) = Self::convert_a_to_sql(query);
+        if indexes.is_empty() {
+            return Ok(Self::execute(&self.conn, sql.as_str(), params.as_slice())?);
+        }
+
+        self.conn.execute_batch("SAVEPOINT indexed")?; // Row and blind index are written together or not at all
+        let written = Self::execute(&self.conn, sql.as_str(), params.as_slice())
+            .and_then(|_| indexes.iter().try_for_each(|update| Self::execute(
+                &self.conn,
+                &format!("UPDATE {} SET {}=?1 WHERE {}=?2", update.table, update.index, update.column),
+                [&update.value, &update.ciphertext]
+            )));
+        let end = if written.is_ok() { "RELEASE indexed" } else { "ROLLBACK TO indexed; RELEASE indexed" };
+        written.and(self.conn.execute_batch(end))?;
+        Ok(())
+    }
+
+    fn get(&self, query: &GQuery, fields: &FieldCipher) -> Result<Rows, Error> {
+        let (sql, params) = Self::convert_g_to_sql(query, fields);
+        Ok(Self::retrieve(&self.conn, sql.as_str(), Some(params))?)
+    }
+
+    fn remove(&self, table: &str, column: &str, value: &str) -> Result<(), Error> {
+        Ok(Self::execute(&self.conn, &format!("DELETE FROM {} WHERE {}=?1", table, column), [value])?)
+    }
+
+    fn wipe(&self) -> Result<(), Error> {
+        let tables = Self::retrieve(&self.conn, "SELECT name FROM sqlite_master WHERE type='table' AND name NOT IN ('sqlite_sequence', 'schema_migrations')", None)?;
+        for row in &tables.values {
+            if let Some(Value::Text(table)) = row.first() {
+                Self::execute(&self.conn, &format!("DELETE FROM {}", table), [])?;
             }
         }
+        Ok(())
+    }
+
+    fn begin(&self) -> Result<(), Error> { // Takes the write lock up front, so pooled connections queue on the busy timeout rather than deadlock on upgrade
+        Ok(Self::execute(&self.conn, "BEGIN IMMEDIATE", [])?)
+    }
+
+    fn commit(&self) -> Result<(), Error> {
+        Ok(Self::execute(&self.conn, "COMMIT", [])?)
+    }
+
+    fn rollback(&self) -> Result<(), Error> {
+        Ok(Self::execute(&self.conn, "ROLLBACK", [])?)
+    }
+
+    fn savepoint(&self, name: &str) -> Result<(), Error> {
+        Ok(Self::execute(&self.conn, &format!("SAVEPOINT {}", name), [])?)
+    }
+
+    fn release(&self, name: &str) -> Result<(), Error> {
+        Ok(Self::execute(&self.conn, &format!("RELEASE {}", name), [])?)
+    }
+
+    fn rollback_to(&self, name: &str) -> Result<(), Error> { // ROLLBACK TO leaves the savepoint open, so it is released after
+        Self::execute(&self.conn, &format!("ROLLBACK TO {}", name), [])?;
+        self.release(name)
+    }
+
+    fn query(&self, sql: &str) -> Result<Rows, Error> {
+        Ok(Self::retrieve(&self.conn, sql, None)?)
+    }
+
+    fn backup(&self, destination: &Path) -> Result<(), BackupError> {
+        let mut copy = Connection::open(destination)?;
+        Backup::new(&self.conn, &mut copy)?.run_to_completion(BACKUP_PAGES, BACKUP_PAUSE, None)?;
+        copy.query_row("PRAGMA journal_mode=DELETE", [], |row| row.get::<_, String>(0))?; // One self-contained file, without -wal and -shm beside it
+        Ok(())
+    }
+
+    fn restore(&mut self, source: &Path) -> Result<(), BackupError> {
+        let backup = Connection::open_with_flags(source, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        let report = match Self::retrieve(&backup, "PRAGMA integrity_check", None) {
+            Ok(rows) => rows.values,
+            Err(e) => return Err(BackupError::Corrupt(e.to_string())) // Damaged badly enough that the check itself stops
+        };
+        let report: Vec<String> = report
+            .iter()
+            .filter_map(|row| match row.first() {
+                Some(Value::Text(line)) => Some(line.clone()),
+                _ => None
+            })
+            .collect();
+        if report != ["ok"] {
+            return Err(BackupError::Corrupt(report.join("; ")));
+        }
+        let (version, live) = (Migrations::version(&backup)?, Migrations::version(&self.conn)?);
+        if version != live {
+            return Err(BackupError::Version { backup: version, live });
+        }
+
+        Backup::new(&backup, &mut self.conn)?.run_to_completion(BACKUP_PAGES, BACKUP_PAUSE, None)?;
+        Ok(())
     }
-}
\ No newline at end of file
+}
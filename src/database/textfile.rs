@@ -1,23 +1,1130 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::Write as IoWrite;
 use std::path::PathBuf;
-use std::error::Error;
+use std::sync::{Mutex, MutexGuard};
 
-use crate::tools::filesystem::FileSystem;
+use rusqlite::types::Value;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value as JsonValue};
 
+use crate::database::access::AccessFilter;
+use crate::database::db::{AQuery, GQuery, DatabaseConnection, DatabaseStruct, Rows, TableStruct};
+use crate::database::fields::{FieldCipher, IndexUpdate};
+use crate::database::migrations::MigrationError;
+use crate::error::Error;
+use crate::login::encrypt::PLAINTEXT_MARK;
 
-pub struct TextFile;
+const COMPACT_SLACK: usize = 256; // Lines allowed beyond twice the live rows before compacting
+
+type Row = Map<String, JsonValue>;
+type Tables = BTreeMap<String, BTreeMap<u64, Row>>; // table -> internal row id -> row
+
+pub enum Condition {
+    Eq(&'static str, JsonValue),
+    Gt(&'static str, i64),
+    Lt(&'static str, i64),
+    NotNull(&'static str),
+    Prefix(&'static str, String),
+    Either(Box<Condition>, Box<Condition>)
+}
+
+pub enum Write {
+    Insert {
+        table: &'static str,
+        row: Row,
+        or_ignore: bool
+    },
+    Update {
+        table: &'static str,
+        set: Row,
+        filter: Vec<Condition>
+    },
+    Delete {
+        table: &'static str,
+        filter: Vec<Condition>
+    }
+}
+
+pub struct Read {
+    pub table: &'static str,
+    pub columns: Vec<&'static str>,
+    pub filter: Vec<Condition>,
+    pub order: Vec<(&'static str, bool)>, // column, descending
+    pub limit: Option<usize>,
+    pub offset: usize,
+    pub aggregate: Option<Aggregate> // Replaces the rows with a single value
+}
+
+pub enum Aggregate {
+    Count,
+    Exists,
+    PerMinute, // Access log rows grouped by minute
+    TopPaths(usize), // Access log rows grouped by path, busiest first
+    Summary // Access log totals
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum Line { // One change per line, replayed in order on open
+    Put {
+        table: String,
+        id: u64,
+        row: Row
+    },
+    Delete {
+        table: String,
+        id: u64
+    }
+}
+
+struct TableDef {
+    name: String,
+    columns: Vec<ColumnDef>,
+    unique: Vec<Vec<String>> // Column sets that may not repeat, primary key included
+}
+
+struct ColumnDef {
+    name: String,
+    default: JsonValue,
+    nullable: bool,
+    auto_increment: bool
+}
+
+#[derive(Default)]
+struct Store {
+    tables: Tables,
+    next_id: u64,
+    lines: usize, // In the file, live or not
+    schema: Vec<TableDef>,
+    max_ids: BTreeMap<String, i64>, // table -> highest auto_increment value handed out
+    unique: BTreeMap<String, Vec<HashMap<String, u64>>>, // table -> one index per unique column set, values -> row id
+    undo: Vec<Undo>, // Rows replaced since the write or transaction began, oldest first
+    transaction: Option<(Mark, Vec<Line>)>, // Where to roll back to and lines not yet written
+    savepoints: Vec<(String, Mark, usize)> // Name, where to roll back to and pending line count, innermost last
+}
+
+struct Undo {
+    table: String,
+    id: u64,
+    previous: Option<Row> // None when the row didn't exist
+}
+
+struct Mark { // Enough to roll the store back, taken before changes
+    undo: usize,
+    next_id: u64,
+    max_ids: BTreeMap<String, i64>
+}
+
+pub struct TextFile { // Fail safe JSONL store, kept in memory and appended to on every change
+    path: PathBuf,
+    _lock: File, // Held for as long as the store is open
+    store: Mutex<Store>
+}
 
 impl TextFile {
-    pub fn open(path: &str) -> Result<PathBuf, Box<dyn Error>> {
-        match FileSystem::check_file_availability(path.to_string(), "db".to_string()) {
-            Some(path_buf) => Ok(path_buf),
-            None => Err(
-                Box::new(
-                    std::io::Error::new(
-                        std::io::ErrorKind::NotFound, 
-                        "File not available"
-                    )
-                ) as Box<dyn Error>
+    pub fn open(path: &str) -> Result<Self, Error> {
+        let path = std::env::current_dir()?.join("db").join(path);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let lock = OpenOptions::new().create(true).truncate(false).write(true).open(path.with_extension("lock"))?;
+        if lock.try_lock().is_err() {
+            return Err(Error::Io(std::io::Error::other(format!("{} is in use by another process", path.display()))));
+        }
+
+        let mut store = Store::default();
+        let mut torn = false; // The next append would be glued onto it, so the file is rewritten first
+        if let Ok(data) = std::fs::read_to_string(&path) {
+            for (number, line) in data.lines().enumerate() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str::<Line>(line) {
+                    Ok(line) => store.replay(line),
+                    Err(e) => { // A torn final line from a crash mid-append
+                        println!("Skipping line {} of {}: {}", number + 1, path.display(), e);
+                        torn = true;
+                    }
+                }
+                store.lines += 1;
+            }
+            torn |= !data.is_empty() && !data.ends_with('\n');
+        }
+
+        let textfile = Self {
+            path,
+            _lock: lock,
+            store: Mutex::new(store)
+        };
+        if torn {
+            textfile.compact()?;
+        }
+        Ok(textfile)
+    }
+
+    pub fn compact(&self) -> Result<(), Error> { // Rewrites the file with one line per live row
+        let mut store = self.lock()?;
+        self.rewrite(&mut store)
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, Store>, Error> {
+        self.store.lock().map_err(|_| Error::database("store lock poisoned"))
+    }
+
+    fn rewrite(&self, store: &mut Store) -> Result<(), Error> { // Written to a temporary file then renamed so a crash can't lose rows
+        let mut data = String::new();
+        for (table, rows) in &store.tables {
+            for (id, row) in rows {
+                data.push_str(&serde_json::to_string(&Line::Put { table: table.clone(), id: *id, row: row.clone() }).map_err(|e| Error::database(e.to_string()))?);
+                data.push('\n');
+            }
+        }
+
+        let temporary = self.path.with_extension("tmp");
+        File::create(&temporary)
+            .and_then(|mut file| file.write_all(data.as_bytes()).and_then(|_| file.sync_all()))
+            .and_then(|_| std::fs::rename(&temporary, &self.path))?;
+        store.lines = store.tables.values().map(|rows| rows.len()).sum();
+        Ok(())
+    }
+
+    fn append(&self, store: &mut Store, lines: Vec<Line>) -> Result<(), Error> {
+        if lines.is_empty() {
+            return Ok(());
+        }
+        if let Some((_, pending)) = &mut store.transaction {
+            pending.extend(lines);
+            return Ok(());
+        }
+
+        let mut data = String::new();
+        for line in &lines {
+            data.push_str(&serde_json::to_string(line).map_err(|e| Error::database(e.to_string()))?);
+            data.push('\n');
+        }
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(data.as_bytes()).and_then(|_| file.sync_data()))?;
+
+        store.lines += lines.len();
+        let live: usize = store.tables.values().map(|rows| rows.len()).sum();
+        if store.lines > live * 2 + COMPACT_SLACK {
+            if let Err(e) = self.rewrite(store) { // The appended lines are safe, compaction is retried on the next append
+                println!("Failed to compact {}: {}", self.path.display(), e);
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&self, writes: Vec<Write>) -> Result<(), Error> { // Applied together or not at all
+        self.change(|store| writes.into_iter().try_fold(Vec::new(), |mut lines, write| {
+            lines.extend(store.apply(write).map_err(Error::database)?);
+            Ok(lines)
+        }))
+    }
+
+    fn change(&self, apply: impl FnOnce(&mut Store) -> Result<Vec<Line>, Error>) -> Result<(), Error> {
+        let mut store = self.lock()?;
+        let mark = store.mark();
+        let written = apply(&mut store).and_then(|lines| self.append(&mut store, lines)); // Memory never runs ahead of the file
+        if written.is_err() {
+            store.undo_to(mark);
+        }
+        if store.transaction.is_none() {
+            store.undo.clear();
+        }
+        written
+    }
+
+    pub fn convert_a_to_write(query: &AQuery) -> Write {
+        match query {
+            AQuery::User { username, password, name, email, site } => Write::Insert {
+                table: "users",
+                row: Self::row(json!({ "username": username, "password": password, "name": name, "email": email, "site": site })),
+                or_ignore: false
+            },
+            AQuery::UserIDAdd { username, password, id } => Self::update("users", json!({ "id": id }), vec![
+                Condition::Eq("username", json!(username)),
+                Condition::Eq("password", json!(password))
+            ]),
+            AQuery::UserPing { username, site } => Self::update("users", json!({ "site": site }), vec![Condition::Eq("username", json!(username))]),
+            AQuery::Password { username, password } => Self::update("users", json!({ "password": password }), vec![Condition::Eq("username", json!(username))]),
+            AQuery::Lock { username, until } => Self::update("users", json!({ "locked_until": until }), vec![Condition::Eq("username", json!(username))]),
+            AQuery::Unlock { username } => Self::update("users", json!({ "locked": 0, "locked_until": 0 }), vec![Condition::Eq("username", json!(username))]),
+            AQuery::Event { timestamp, kind, ip, username, detail } => Write::Insert {
+                table: "events",
+                row: Self::row(json!({ "timestamp": timestamp, "kind": kind, "ip": ip, "username": username, "detail": detail })),
+                or_ignore: false
+            },
+            AQuery::Session { token, username, created, expires } => Write::Insert {
+                table: "sessions",
+                row: Self::row(json!({ "token": token, "username": username, "created": created, "expires": expires })),
+                or_ignore: false
+            },
+            AQuery::EndSession { token } => Write::Delete { table: "sessions", filter: vec![Condition::Eq("token", json!(token))] },
+            AQuery::Role { username, role } => Write::Insert {
+                table: "roles",
+                row: Self::row(json!({ "username": username, "role": role })),
+                or_ignore: true
+            },
+            AQuery::RevokeRole { username, role } => Write::Delete { table: "roles", filter: vec![
+                Condition::Eq("username", json!(username)),
+                Condition::Eq("role", json!(role))
+            ] },
+            AQuery::SigningKey { kid, key, created } => Write::Insert {
+                table: "signing_keys",
+                row: Self::row(json!({ "kid": kid, "key": key, "created": created })),
+                or_ignore: false
+            },
+            AQuery::SigningKeyRewrap { kid, key } => Self::update("signing_keys", json!({ "key": key }), vec![Condition::Eq("kid", json!(kid))]),
+            AQuery::RetireSigningKeys { retired } => Self::update("signing_keys", json!({ "retired": retired }), vec![Condition::Eq("retired", json!(0))]),
+            AQuery::TotpSecret { username, secret } => Self::update(
+                "users",
+                json!({ "totp_secret": secret, "totp_enabled": 0, "totp_last_step": 0 }),
+                vec![Condition::Eq("username", json!(username))]
+            ),
+            AQuery::TotpRewrap { username, secret } => Self::update("users", json!({ "totp_secret": secret }), vec![Condition::Eq("username", json!(username))]),
+            AQuery::TotpEnable { username } => Self::update("users", json!({ "totp_enabled": 1 }), vec![
+                Condition::Eq("username", json!(username)),
+                Condition::NotNull("totp_secret")
+            ]),
+            AQuery::TotpStep { username, step } => Self::update("users", json!({ "totp_last_step": step }), vec![Condition::Eq("username", json!(username))]),
+            AQuery::TotpDisable { username } => Self::update(
+                "users",
+                json!({ "totp_secret": null, "totp_enabled": 0, "totp_last_step": 0 }),
+                vec![Condition::Eq("username", json!(username))]
             ),
+            AQuery::RecoveryCode { username, code } => Write::Insert {
+                table: "recovery_codes",
+                row: Self::row(json!({ "username": username, "code": code })),
+                or_ignore: false
+            },
+            AQuery::UseRecoveryCode { username, code } => Write::Delete { table: "recovery_codes", filter: vec![
+                Condition::Eq("username", json!(username)),
+                Condition::Eq("code", json!(code))
+            ] },
+            AQuery::ClearRecoveryCodes { username } => Write::Delete { table: "recovery_codes", filter: vec![Condition::Eq("username", json!(username))] },
+            AQuery::Challenge { token, username, grant, expires } => Write::Insert {
+                table: "challenges",
+                row: Self::row(json!({ "token": token, "username": username, "grant": grant, "expires": expires })),
+                or_ignore: false
+            },
+            AQuery::EndChallenge { token } => Write::Delete { table: "challenges", filter: vec![Condition::Eq("token", json!(token))] },
+            AQuery::EndSessions { username } => Write::Delete { table: "sessions", filter: vec![Condition::Eq("username", json!(username))] },
+            AQuery::Reset { token, username, expires } => Write::Insert {
+                table: "password_resets",
+                row: Self::row(json!({ "token": token, "username": username, "expires": expires })),
+                or_ignore: false
+            },
+            AQuery::EndResets { username } => Write::Delete { table: "password_resets", filter: vec![Condition::Eq("username", json!(username))] },
+            AQuery::ApiKey { id, username, label, hash, scopes, created } => Write::Insert {
+                table: "api_keys",
+                row: Self::row(json!({ "id": id, "username": username, "label": label, "hash": hash, "scopes": scopes, "created": created })),
+                or_ignore: false
+            },
+            AQuery::RevokeApiKey { id, username } => Self::update("api_keys", json!({ "revoked": 1 }), vec![
+                Condition::Eq("id", json!(id)),
+                Condition::Eq("username", json!(username))
+            ]),
+            AQuery::ApiKeyUsed { hash, used } => Self::update("api_keys", json!({ "last_used": used }), vec![Condition::Eq("hash", json!(hash))]),
+            AQuery::Profile { username, name, email, site } => {
+                let mut set = Row::new();
+                for (column, value) in [("name", name), ("email", email), ("site", site)] {
+                    if let Some(value) = value {
+                        set.insert(column.to_string(), json!(value));
+                    }
+                }
+                Write::Update { table: "users", set, filter: vec![Condition::Eq("username", json!(username))] }
+            },
+            AQuery::DeleteUser { username } => Write::Delete { table: "users", filter: vec![Condition::Eq("username", json!(username))] },
+            AQuery::RevokeRoles { username } => Write::Delete { table: "roles", filter: vec![Condition::Eq("username", json!(username))] },
+            AQuery::DeleteApiKeys { username } => Write::Delete { table: "api_keys", filter: vec![Condition::Eq("username", json!(username))] },
+            AQuery::FieldRewrap { column, ciphertext, rotated, index } => {
+                let mut set = Row::new();
+                set.insert(column.column.to_string(), json!(rotated));
+                if let (Some(index_column), Some(index)) = (column.index, index) {
+                    set.insert(index_column.to_string(), json!(index));
+                }
+                Write::Update { table: column.table, set, filter: vec![Condition::Eq(column.column, json!(ciphertext))] }
+            },
+            AQuery::RevokeApiKeys { username } => Self::update("api_keys", json!({ "revoked": 1 }), vec![Condition::Eq("username", json!(username))]),
+            AQuery::RevokeTokens { username, before } => Self::update("users", json!({ "tokens_revoked": before }), vec![Condition::Eq("username", json!(username))]),
+            AQuery::Access { timestamp, ip, method, path, status, bytes, duration_ms, username, user_agent } => Write::Insert {
+                table: "access_log",
+                row: Self::row(json!({
+                    "timestamp": timestamp,
+                    "ip": ip,
+                    "method": method,
+                    "path": path,
+                    "status": status,
+                    "bytes": bytes,
+                    "duration_ms": duration_ms,
+                    "username": username,
+                    "user_agent": user_agent
+                })),
+                or_ignore: false
+            },
+            AQuery::PruneAccess { before } => Write::Delete { table: "access_log", filter: vec![Condition::Lt("timestamp", *before)] }
+        }
+    }
+
+    pub fn convert_g_to_read(query: &GQuery, fields: &FieldCipher) -> Read {
+        let select = |table, columns: &[&'static str], filter| Read {
+            table,
+            columns: columns.to_vec(),
+            filter,
+            order: Vec::new(),
+            limit: None,
+            offset: 0,
+            aggregate: None
+        };
+        match query {
+            GQuery::Password { username } => select("users", &["password"], vec![Condition::Eq("username", json!(username))]),
+            GQuery::UserData { username } => select("users", &["name", "email"], vec![Condition::Eq("username", json!(username))]),
+            GQuery::Email { email } => {
+                let column = match fields.lookup_column("users", "email") {
+                    "email_index" => "email_index",
+                    _ => "email"
+                };
+                select("users", &["username"], vec![Condition::Eq(column, json!(email))])
+            },
+            GQuery::User { username } => select("users", &["username", "name", "email", "site"], vec![Condition::Eq("username", json!(username))]),
+            GQuery::Status { username } => select("users", &["locked", "disabled", "locked_until"], vec![Condition::Eq("username", json!(username))]),
+            GQuery::Session { token, now } => select("sessions", &["username"], vec![
+                Condition::Eq("token", json!(token)),
+                Condition::Gt("expires", *now)
+            ]),
+            GQuery::Roles { username } => select("roles", &["role"], vec![Condition::Eq("username", json!(username))]),
+            GQuery::ActiveSigningKey => Read {
+                order: vec![("created", true)],
+                limit: Some(1),
+                ..select("signing_keys", &["kid", "key"], vec![Condition::Eq("retired", json!(0))])
+            },
+            GQuery::SigningKey { kid, since } => select("signing_keys", &["key"], vec![
+                Condition::Eq("kid", json!(kid)),
+                Condition::Either(Box::new(Condition::Eq("retired", json!(0))), Box::new(Condition::Gt("retired", *since)))
+            ]),
+            GQuery::TotpSecret { username } => select("users", &["totp_secret"], vec![Condition::Eq("username", json!(username))]),
+            GQuery::TotpState { username } => select("users", &["totp_enabled", "totp_last_step"], vec![Condition::Eq("username", json!(username))]),
+            GQuery::RecoveryCode { username, code } => select("recovery_codes", &["code"], vec![
+                Condition::Eq("username", json!(username)),
+                Condition::Eq("code", json!(code))
+            ]),
+            GQuery::Challenge { token, now } => select("challenges", &["username", "grant"], vec![
+                Condition::Eq("token", json!(token)),
+                Condition::Gt("expires", *now)
+            ]),
+            GQuery::Reset { token, now } => select("password_resets", &["username"], vec![
+                Condition::Eq("token", json!(token)),
+                Condition::Gt("expires", *now)
+            ]),
+            GQuery::ApiKey { hash } => select("api_keys", &["username", "scopes"], vec![
+                Condition::Eq("hash", json!(hash)),
+                Condition::Eq("revoked", json!(0))
+            ]),
+            GQuery::ApiKeys { username } => Read {
+                order: vec![("created", false)],
+                ..select("api_keys", &["id", "label", "scopes", "created", "last_used", "revoked"], vec![Condition::Eq("username", json!(username))])
+            },
+            GQuery::Users { order, descending, limit, offset } => Read {
+                order: vec![(order.column(), *descending)],
+                limit: Some((*limit).max(0) as usize),
+                offset: (*offset).max(0) as usize,
+                ..select("users", &["username", "name", "email", "site"], vec![])
+            },
+            GQuery::UserCount => Read {
+                aggregate: Some(Aggregate::Count),
+                ..select("users", &[], vec![])
+            },
+            GQuery::UserExists { username } => Read {
+                aggregate: Some(Aggregate::Exists),
+                ..select("users", &[], vec![Condition::Eq("username", json!(username))])
+            },
+            GQuery::PlaintextFields { column } => select(column.table, &[column.column], vec![Condition::Prefix(column.column, PLAINTEXT_MARK.to_string())]),
+            GQuery::TokensRevoked { username } => select("users", &["tokens_revoked"], vec![Condition::Eq("username", json!(username))]),
+            GQuery::AccessEntries { filter, limit, offset } => Read {
+                order: vec![("timestamp", true), ("id", true)],
+                limit: Some((*limit).max(0) as usize),
+                offset: (*offset).max(0) as usize,
+                ..select(
+                    "access_log",
+                    &["timestamp", "ip", "method", "path", "status", "bytes", "duration_ms", "username", "user_agent"],
+                    Self::access_filter(filter)
+                )
+            },
+            GQuery::AccessPerMinute { filter } => Read {
+                aggregate: Some(Aggregate::PerMinute),
+                ..select("access_log", &[], Self::access_filter(filter))
+            },
+            GQuery::AccessTopPaths { filter, limit } => Read {
+                aggregate: Some(Aggregate::TopPaths((*limit).max(0) as usize)),
+                ..select("access_log", &[], Self::access_filter(filter))
+            },
+            GQuery::AccessSummary { filter } => Read {
+                aggregate: Some(Aggregate::Summary),
+                ..select("access_log", &[], Self::access_filter(filter))
+            }
+        }
+    }
+
+    fn access_filter(filter: &AccessFilter) -> Vec<Condition> { // Mirrors ACCESS_FILTER in sqlite.rs
+        let mut conditions = Vec::new();
+        if let Some(since) = filter.since {
+            conditions.push(Condition::Gt("timestamp", since.saturating_sub(1)));
+        }
+        if let Some(until) = filter.until {
+            conditions.push(Condition::Lt("timestamp", until));
+        }
+        if let Some(class) = filter.status_class {
+            conditions.push(Condition::Gt("status", class.saturating_mul(100).saturating_sub(1)));
+            conditions.push(Condition::Lt("status", class.saturating_add(1).saturating_mul(100)));
+        }
+        if let Some(prefix) = &filter.path_prefix {
+            conditions.push(Condition::Prefix("path", prefix.clone()));
+        }
+        if let Some(ip) = &filter.ip {
+            conditions.push(Condition::Eq("ip", json!(ip)));
+        }
+        if let Some(username) = &filter.username {
+            conditions.push(Condition::Eq("username", json!(username)));
+        }
+        conditions
+    }
+
+    fn update(table: &'static str, set: JsonValue, filter: Vec<Condition>) -> Write {
+        Write::Update { table, set: Self::row(set), filter }
+    }
+
+    fn row(value: JsonValue) -> Row {
+        match value {
+            JsonValue::Object(row) => row,
+            _ => Row::new()
+        }
+    }
+}
+
+impl Store {
+    fn replay(&mut self, line: Line) {
+        match line {
+            Line::Put { table, id, row } => {
+                self.next_id = self.next_id.max(id + 1);
+                self.tables.entry(table).or_default().insert(id, row);
+            },
+            Line::Delete { table, id } => {
+                if let Some(rows) = self.tables.get_mut(&table) {
+                    rows.remove(&id);
+                }
+            }
+        }
+    }
+
+    fn apply(&mut self, write: Write) -> Result<Vec<Line>, String> {
+        match write {
+            Write::Insert { table, row, or_ignore } => self.insert(table, row, or_ignore),
+            Write::Update { table, set, filter } => self.update(table, set, &filter),
+            Write::Delete { table, filter } => {
+                self.definition(table)?;
+                Ok(self.delete(table, |row| Condition::all(&filter, row)))
+            }
+        }
+    }
+
+    fn delete(&mut self, table: &str, matches: impl Fn(&Row) -> bool) -> Vec<Line> {
+        let ids: Vec<u64> = self.tables
+            .get(table)
+            .into_iter()
+            .flatten()
+            .filter(|(_, row)| matches(row))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in &ids {
+            self.set(table, *id, None);
+        }
+        ids.into_iter().map(|id| Line::Delete { table: table.to_string(), id }).collect()
+    }
+
+    fn set(&mut self, table: &str, id: u64, row: Option<Row>) { // Every change goes through here, so it can be undone
+        let previous = self.put(table, id, row);
+        self.undo.push(Undo { table: table.to_string(), id, previous });
+    }
+
+    fn put(&mut self, table: &str, id: u64, row: Option<Row>) -> Option<Row> { // Keeps the unique indexes and highest ids in step with the rows
+        let rows = self.tables.entry(table.to_string()).or_default();
+        let previous = match row {
+            Some(row) => rows.insert(id, row),
+            None => rows.remove(&id)
+        };
+        let definition = match self.schema.iter().find(|definition| definition.name == table) {
+            Some(definition) => definition,
+            None => return previous // Replayed before init, indexed once the schema is known
+        };
+
+        let indexes = self.unique.entry(table.to_string()).or_insert_with(|| vec![HashMap::new(); definition.unique.len()]);
+        if let Some(previous) = &previous {
+            for (columns, index) in definition.unique.iter().zip(indexes.iter_mut()) {
+                if let Some(key) = Self::unique_key(columns, previous) {
+                    if index.get(&key) == Some(&id) {
+                        index.remove(&key);
+                    }
+                }
+            }
+        }
+        if let Some(row) = self.tables.get(table).and_then(|rows| rows.get(&id)) {
+            for (columns, index) in definition.unique.iter().zip(indexes.iter_mut()) {
+                if let Some(key) = Self::unique_key(columns, row) {
+                    index.insert(key, id);
+                }
+            }
+            for column in definition.columns.iter().filter(|column| column.auto_increment) {
+                if let Some(value) = row.get(&column.name).and_then(JsonValue::as_i64) {
+                    let max = self.max_ids.entry(table.to_string()).or_insert(0);
+                    *max = (*max).max(value);
+                }
+            }
+        }
+        previous
+    }
+
+    fn reindex(&mut self) { // After the schema changes, rebuilds the indexes from the rows
+        self.unique.clear();
+        self.max_ids.clear();
+        for (table, rows) in std::mem::take(&mut self.tables) {
+            for (id, row) in rows {
+                self.put(&table, id, Some(row));
+            }
+        }
+    }
+
+    fn mark(&self) -> Mark {
+        Mark {
+            undo: self.undo.len(),
+            next_id: self.next_id,
+            max_ids: self.max_ids.clone()
+        }
+    }
+
+    fn undo_to(&mut self, mark: Mark) {
+        while self.undo.len() > mark.undo {
+            if let Some(undo) = self.undo.pop() {
+                self.put(&undo.table, undo.id, undo.previous);
+            }
+        }
+        self.next_id = mark.next_id;
+        self.max_ids = mark.max_ids;
+    }
+
+    fn insert(&mut self, table: &str, mut row: Row, or_ignore: bool) -> Result<Vec<Line>, String> {
+        let definition = self.definition(table)?;
+        Self::check_columns(definition, &row)?;
+        for column in &definition.columns {
+            if row.get(&column.name).is_none_or(JsonValue::is_null) {
+                let value = if column.auto_increment {
+                    json!(self.max_ids.get(table).copied().unwrap_or(0) + 1)
+                } else {
+                    column.default.clone()
+                };
+                row.insert(column.name.clone(), value);
+            }
+            if !column.nullable && row[&column.name].is_null() {
+                return Err(format!("NOT NULL constraint failed: {}.{}", table, column.name));
+            }
+        }
+        if let Some(conflict) = self.conflict(definition, None, &row) {
+            return if or_ignore {
+                Ok(Vec::new())
+            } else {
+                Err(format!("UNIQUE constraint failed: {}.{}", table, conflict))
+            };
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.set(table, id, Some(row.clone()));
+        Ok(vec![Line::Put { table: table.to_string(), id, row }])
+    }
+
+    fn update(&mut self, table: &str, set: Row, filter: &[Condition]) -> Result<Vec<Line>, String> {
+        let definition = self.definition(table)?;
+        Self::check_columns(definition, &set)?;
+        let mut changed = Vec::new();
+        for (id, row) in self.tables.get(table).into_iter().flatten().filter(|(_, row)| Condition::all(filter, row)) {
+            let mut updated = row.clone();
+            for (column, value) in &set {
+                updated.insert(column.clone(), value.clone());
+            }
+            if let Some(column) = definition.columns.iter().find(|column| !column.nullable && updated[&column.name].is_null()) {
+                return Err(format!("NOT NULL constraint failed: {}.{}", table, column.name));
+            }
+            changed.push((*id, updated));
+        }
+
+        let mut lines = Vec::with_capacity(changed.len());
+        for (id, row) in changed { // Checked one row at a time as in SQLite, the caller undoes a partial update
+            if let Some(conflict) = self.conflict(self.definition(table)?, Some(id), &row) {
+                return Err(format!("UNIQUE constraint failed: {}.{}", table, conflict));
+            }
+            self.set(table, id, Some(row.clone()));
+            lines.push(Line::Put { table: table.to_string(), id, row });
+        }
+        Ok(lines)
+    }
+
+    fn read(&self, read: &Read) -> Result<Rows, String> {
+        self.definition(read.table)?;
+        let mut matched: Vec<&Row> = self.tables
+            .get(read.table)
+            .into_iter()
+            .flat_map(|rows| rows.values())
+            .filter(|row| Condition::all(&read.filter, row))
+            .collect();
+        matched.sort_by(|a, b| {
+            read.order
+                .iter()
+                .map(|(column, descending)| {
+                    let ordering = Self::compare(a.get(*column), b.get(*column));
+                    if *descending { ordering.reverse() } else { ordering }
+                })
+                .find(|ordering| ordering.is_ne())
+                .unwrap_or(Ordering::Equal)
+        });
+        match read.aggregate {
+            Some(Aggregate::Count) => return Ok(Rows { columns: vec![String::from("count")], values: vec![vec![Value::Integer(matched.len() as i64)]] }),
+            Some(Aggregate::Exists) => return Ok(Rows { columns: vec![String::from("exists")], values: vec![vec![Value::Integer(!matched.is_empty() as i64)]] }),
+            Some(Aggregate::PerMinute) => {
+                let mut minutes: BTreeMap<i64, [i64; 3]> = BTreeMap::new();
+                for row in &matched {
+                    let minute = Self::integer(row, "timestamp").div_euclid(60) * 60;
+                    Self::tally(minutes.entry(minute).or_default(), row);
+                }
+                return Ok(Self::grouped(&["minute"], minutes.into_iter().map(|(minute, counts)| (Value::Integer(minute), counts)).collect()));
+            },
+            Some(Aggregate::TopPaths(limit)) => {
+                let mut paths: BTreeMap<String, [i64; 3]> = BTreeMap::new();
+                for row in &matched {
+                    let path = row.get("path").and_then(JsonValue::as_str).unwrap_or_default().to_string();
+                    Self::tally(paths.entry(path).or_default(), row);
+                }
+                let mut paths: Vec<(String, [i64; 3])> = paths.into_iter().collect();
+                paths.sort_by(|a, b| b.1[0].cmp(&a.1[0])); // Stable, so ties stay in path order
+                paths.truncate(limit);
+                return Ok(Self::grouped(&["path"], paths.into_iter().map(|(path, counts)| (Value::Text(path), counts)).collect()));
+            },
+            Some(Aggregate::Summary) => {
+                let mut counts = [0; 3];
+                for row in &matched {
+                    Self::tally(&mut counts, row);
+                }
+                let sum = |column| matched.iter().map(|row| Self::integer(row, column)).sum::<i64>();
+                return Ok(Rows {
+                    columns: ["requests", "client_errors", "server_errors", "duration_ms", "bytes"].iter().map(|column| column.to_string()).collect(),
+                    values: vec![vec![
+                        Value::Integer(counts[0]),
+                        Value::Integer(counts[1]),
+                        Value::Integer(counts[2]),
+                        Value::Integer(sum("duration_ms")),
+                        Value::Integer(sum("bytes"))
+                    ]]
+                });
+            },
+            None => {}
+        }
+
+        let values = matched
+            .into_iter()
+            .skip(read.offset)
+            .take(read.limit.unwrap_or(usize::MAX))
+            .map(|row| read.columns
+                .iter()
+                .map(|column| Self::to_value(row.get(*column).unwrap_or(&JsonValue::Null)))
+                .collect())
+            .collect();
+        Ok(Rows {
+            columns: read.columns.iter().map(|column| column.to_string()).collect(),
+            values
+        })
+    }
+
+    fn integer(row: &Row, column: &str) -> i64 {
+        row.get(column).and_then(JsonValue::as_i64).unwrap_or(0)
+    }
+
+    fn tally(counts: &mut [i64; 3], row: &Row) { // requests, client errors, server errors
+        let status = Self::integer(row, "status");
+        counts[0] += 1;
+        counts[1] += (400..500).contains(&status) as i64;
+        counts[2] += (status >= 500) as i64;
+    }
+
+    fn grouped(key: &[&str], groups: Vec<(Value, [i64; 3])>) -> Rows {
+        Rows {
+            columns: key.iter().chain(&["requests", "client_errors", "server_errors"]).map(|column| column.to_string()).collect(),
+            values: groups
+                .into_iter()
+                .map(|(key, counts)| std::iter::once(key).chain(counts.iter().map(|count| Value::Integer(*count))).collect())
+                .collect()
+        }
+    }
+
+    fn compare(a: Option<&JsonValue>, b: Option<&JsonValue>) -> Ordering { // SQLite's order, NULLs then numbers then text
+        let rank = |value: Option<&JsonValue>| match value {
+            None | Some(JsonValue::Null) => 0,
+            Some(JsonValue::Bool(_)) | Some(JsonValue::Number(_)) => 1,
+            Some(JsonValue::String(_)) => 2,
+            Some(_) => 3
+        };
+        match (a, b) {
+            (Some(JsonValue::Number(a)), Some(JsonValue::Number(b))) => a.as_f64().partial_cmp(&b.as_f64()).unwrap_or(Ordering::Equal),
+            (Some(JsonValue::String(a)), Some(JsonValue::String(b))) => a.cmp(b),
+            _ => rank(a).cmp(&rank(b))
+        }
+    }
+
+    fn definition(&self, table: &str) -> Result<&TableDef, String> {
+        self.schema
+            .iter()
+            .find(|definition| definition.name == table)
+            .ok_or_else(|| format!("no such table: {}", table))
+    }
+
+    fn check_columns(definition: &TableDef, row: &Row) -> Result<(), String> {
+        match row.keys().find(|key| !definition.columns.iter().any(|column| &column.name == *key)) {
+            Some(key) => Err(format!("table {} has no column named {}", definition.name, key)),
+            None => Ok(())
+        }
+    }
+
+    fn conflict(&self, definition: &TableDef, skip: Option<u64>, row: &Row) -> Option<String> { // -> the repeated columns
+        let indexes = self.unique.get(&definition.name)?;
+        definition.unique.iter().zip(indexes).find_map(|(columns, index)| {
+            let owner = index.get(&Self::unique_key(columns, row)?)?;
+            if Some(*owner) != skip {
+                Some(columns.join(", "))
+            } else {
+                None
+            }
+        })
+    }
+
+    fn unique_key(columns: &[String], row: &Row) -> Option<String> { // None when a value is NULL, NULLs never clash as in SQL
+        let values: Vec<&JsonValue> = columns.iter().map(|column| row.get(column).unwrap_or(&JsonValue::Null)).collect();
+        if values.iter().any(|value| value.is_null()) {
+            return None;
+        }
+        serde_json::to_string(&values).ok()
+    }
+
+    fn to_value(value: &JsonValue) -> Value {
+        match value {
+            JsonValue::Null => Value::Null,
+            JsonValue::Bool(flag) => Value::Integer(*flag as i64),
+            JsonValue::Number(number) => match number.as_i64() {
+                Some(integer) => Value::Integer(integer),
+                None => Value::Real(number.as_f64().unwrap_or_default())
+            },
+            JsonValue::String(string) => Value::Text(string.clone()),
+            other => Value::Text(other.to_string())
         }
     }
-}
\ No newline at end of file
+}
+
+impl TableDef {
+    fn from(table: &TableStruct) -> Self {
+        let mut unique: Vec<Vec<String>> = Vec::new();
+        if !table.primary_key.is_empty() {
+            unique.push(table.primary_key.iter().map(|column| column.to_string()).collect());
+        }
+        for column in table.columns.iter().filter(|column| column.unique || column.auto_increment) {
+            unique.push(vec![column.name.to_string()]);
+        }
+        for index in table.indexes.iter().filter(|index| index.unique) {
+            unique.push(index.columns.iter().map(|column| column.to_string()).collect());
+        }
+
+        Self {
+            name: table.name.to_string(),
+            columns: table.columns
+                .iter()
+                .map(|column| ColumnDef {
+                    name: column.name.to_string(),
+                    default: match column.default {
+                        Some(default) => match default.parse::<i64>() {
+                            Ok(integer) => json!(integer),
+                            Err(_) => json!(default.trim_matches('\''))
+                        },
+                        None => JsonValue::Null
+                    },
+                    nullable: column.nullable && !table.primary_key.contains(&column.name),
+                    auto_increment: column.auto_increment
+                })
+                .collect(),
+            unique
+        }
+    }
+}
+
+impl Condition {
+    fn matches(&self, row: &Row) -> bool {
+        match self {
+            Condition::Eq(column, value) => row.get(*column) == Some(value),
+            Condition::Gt(column, bound) => row.get(*column).and_then(JsonValue::as_i64).is_some_and(|value| value > *bound),
+            Condition::Lt(column, bound) => row.get(*column).and_then(JsonValue::as_i64).is_some_and(|value| value < *bound),
+            Condition::Prefix(column, prefix) => row.get(*column).and_then(JsonValue::as_str).is_some_and(|value| value.starts_with(prefix.as_str())),
+            Condition::NotNull(column) => row.get(*column).is_some_and(|value| !value.is_null()),
+            Condition::Either(first, second) => first.matches(row) || second.matches(row)
+        }
+    }
+
+    fn all(filter: &[Condition], row: &Row) -> bool {
+        filter.iter().all(|condition| condition.matches(row))
+    }
+}
+
+impl DatabaseConnection for TextFile {
+    fn name(&self) -> &'static str {
+        "textfile"
+    }
+
+    fn init(&self, this_db: &DatabaseStruct) -> Result<(), MigrationError> { // Schemaless on disk, the tables only describe constraints
+        let fresh = match self.store.lock() {
+            Ok(mut store) => {
+                store.schema = this_db.tables.iter().map(TableDef::from).collect();
+                store.reindex();
+                store.lines == 0
+            },
+            Err(_) => return Err(MigrationError::Failed { version: 0, error: String::from("store lock poisoned") })
+        };
+        if fresh {
+            (this_db.onload)()
+                .and_then(|seeds| self.write(seeds.iter().map(Self::convert_a_to_write).collect()))
+                .map_err(|e| MigrationError::Failed { version: 0, error: format!("onload seed failed: {}", e) })?;
+        }
+        Ok(())
+    }
+
+    fn add(&self, query: &AQuery, indexes: &[IndexUpdate]) -> Result<(), Error> {
+        let mut writes = vec![Self::convert_a_to_write(query)];
+        for update in indexes {
+            let mut set = Row::new();
+            set.insert(update.index.to_string(), json!(update.value));
+            writes.push(Write::Update {
+                table: update.table,
+                set,
+                filter: vec![Condition::Eq(update.column, json!(update.ciphertext))]
+            });
+        }
+        self.write(writes)
+    }
+
+    fn get(&self, query: &GQuery, fields: &FieldCipher) -> Result<Rows, Error> {
+        let read = Self::convert_g_to_read(query, fields);
+        self.lock()?.read(&read).map_err(Error::database)
+    }
+
+    fn remove(&self, table: &str, column: &str, value: &str) -> Result<(), Error> {
+        let value = json!(value);
+        self.change(|store| Ok(store.delete(table, |row| row.get(column) == Some(&value))))
+    }
+
+    fn wipe(&self) -> Result<(), Error> {
+        let mut store = self.lock()?;
+        store.tables.clear();
+        store.reindex();
+        store.undo.clear();
+        self.rewrite(&mut store)
+    }
+
+    fn begin(&self) -> Result<(), Error> {
+        let mut store = self.lock()?;
+        if store.transaction.is_some() {
+            return Err(Error::database("a transaction is already open"));
+        }
+        store.transaction = Some((store.mark(), Vec::new()));
+        Ok(())
+    }
+
+    fn commit(&self) -> Result<(), Error> {
+        let mut store = self.lock()?;
+        store.savepoints.clear();
+        let (mark, lines) = store.transaction.take().ok_or_else(|| Error::database("no transaction is open"))?;
+        let written = self.append(&mut store, lines);
+        if written.is_err() { // Nothing reached the file, so nothing stays in memory either
+            store.undo_to(mark);
+        }
+        store.undo.clear();
+        written
+    }
+
+    fn rollback(&self) -> Result<(), Error> {
+        let mut store = self.lock()?;
+        store.savepoints.clear();
+        let (mark, _) = store.transaction.take().ok_or_else(|| Error::database("no transaction is open"))?;
+        store.undo_to(mark);
+        store.undo.clear();
+        Ok(())
+    }
+
+    fn savepoint(&self, name: &str) -> Result<(), Error> {
+        let mut store = self.lock()?;
+        let pending = match &store.transaction {
+            Some((_, lines)) => lines.len(),
+            None => return Err(Error::database("no transaction is open"))
+        };
+        let mark = store.mark();
+        store.savepoints.push((name.to_string(), mark, pending));
+        Ok(())
+    }
+
+    fn release(&self, name: &str) -> Result<(), Error> {
+        let mut store = self.lock()?;
+        if store.savepoints.last().is_none_or(|savepoint| savepoint.0 != name) {
+            return Err(Error::database(format!("no savepoint {}", name)));
+        }
+        store.savepoints.pop();
+        Ok(())
+    }
+
+    fn rollback_to(&self, name: &str) -> Result<(), Error> {
+        let mut store = self.lock()?;
+        if store.savepoints.last().is_none_or(|savepoint| savepoint.0 != name) {
+            return Err(Error::database(format!("no savepoint {}", name)));
+        }
+        match (store.savepoints.pop(), &mut store.transaction) {
+            (Some((_, mark, pending)), Some((_, lines))) => {
+                lines.truncate(pending);
+                store.undo_to(mark);
+                Ok(())
+            },
+            _ => Err(Error::database("no transaction is open"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::db::ColumnStruct;
+    use crate::tools::config::DType;
+
+    struct Fixture(PathBuf); // Removes the store and its lock file when the test ends
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+            let _ = std::fs::remove_file(self.0.with_extension("lock"));
+        }
+    }
+
+    fn items() -> DatabaseStruct<'static> {
+        DatabaseStruct {
+            src: "items",
+            tables: vec![TableStruct::new("items", vec![
+                ColumnStruct::new("id", DType::Integer).auto_increment(),
+                ColumnStruct::new("name", DType::String).not_null().unique(),
+                ColumnStruct::new("count", DType::Integer).not_null().default("0")
+            ])],
+            migrations: Vec::new(),
+            onload: || Ok(Vec::new())
+        }
+    }
+
+    fn open(fixture: &Fixture) -> TextFile { // An absolute path replaces the db/ directory
+        let store = TextFile::open(fixture.0.to_str().unwrap()).unwrap();
+        store.init(&items()).unwrap();
+        store
+    }
+
+    fn insert(name: &str) -> Write {
+        Write::Insert { table: "items", row: TextFile::row(json!({ "name": name })), or_ignore: false }
+    }
+
+    fn names(store: &TextFile) -> Vec<(i64, String)> {
+        let read = Read { table: "items", columns: vec!["id", "name"], filter: Vec::new(), order: vec![("id", false)], limit: None, offset: 0, aggregate: None };
+        store.lock().unwrap().read(&read).unwrap().values
+            .into_iter()
+            .map(|row| match (&row[0], &row[1]) {
+                (Value::Integer(id), Value::Text(name)) => (*id, name.clone()),
+                _ => panic!("unexpected row")
+            })
+            .collect()
+    }
+
+    fn fixture() -> Fixture {
+        Fixture(std::env::temp_dir().join(format!("textfile-{}.txt", &crate::login::encrypt::Keys::token()[..12])))
+    }
+
+    #[test]
+    fn replays_changes() {
+        let fixture = fixture();
+        {
+            let store = open(&fixture);
+            store.write(vec![insert("a"), insert("b"), insert("c")]).unwrap();
+            store.write(vec![Write::Update { table: "items", set: TextFile::row(json!({ "name": "bee" })), filter: vec![Condition::Eq("name", json!("b"))] }]).unwrap();
+            store.remove("items", "name", "c").unwrap();
+        }
+
+        let store = open(&fixture);
+        assert_eq!(names(&store), vec![(1, String::from("a")), (2, String::from("bee"))]);
+        assert!(store.write(vec![insert("a")]).is_err()); // Unique index rebuilt on open
+        store.write(vec![insert("b")]).unwrap();
+        assert_eq!(names(&store).last(), Some(&(3, String::from("b"))));
+
+        store.compact().unwrap();
+        drop(store);
+        assert_eq!(names(&open(&fixture)).len(), 3);
+    }
+
+    #[test]
+    fn drops_a_torn_final_line() {
+        let fixture = fixture();
+        open(&fixture).write(vec![insert("a")]).unwrap();
+        OpenOptions::new().append(true).open(&fixture.0).unwrap().write_all(br#"{"op":"put","table":"items","id":7,"ro"#).unwrap();
+
+        open(&fixture).write(vec![insert("b")]).unwrap();
+        assert_eq!(names(&open(&fixture)), vec![(1, String::from("a")), (2, String::from("b"))]);
+    }
+
+    #[test]
+    fn failed_batch_keeps_nothing() {
+        let fixture = fixture();
+        let store = open(&fixture);
+        store.write(vec![insert("a")]).unwrap();
+        let error = store.write(vec![insert("b"), insert("a")]).unwrap_err();
+        assert!(error.to_string().contains("UNIQUE constraint failed: items.name"));
+        assert!(store.write(vec![Write::Update { table: "items", set: TextFile::row(json!({ "name": null })), filter: Vec::new() }]).is_err());
+
+        store.write(vec![insert("b")]).unwrap(); // Neither the name nor the id was taken
+        assert_eq!(names(&store), vec![(1, String::from("a")), (2, String::from("b"))]);
+        drop(store);
+        assert_eq!(names(&open(&fixture)).len(), 2);
+    }
+
+    #[test]
+    fn rolls_back_transactions_and_savepoints() {
+        let fixture = fixture();
+        let store = open(&fixture);
+        store.begin().unwrap();
+        store.write(vec![insert("a")]).unwrap();
+        store.savepoint("inner").unwrap();
+        store.write(vec![insert("b")]).unwrap();
+        store.remove("items", "name", "a").unwrap();
+        store.rollback_to("inner").unwrap();
+        assert_eq!(names(&store), vec![(1, String::from("a"))]);
+        store.commit().unwrap();
+
+        store.begin().unwrap();
+        store.write(vec![insert("c")]).unwrap();
+        store.remove("items", "name", "a").unwrap();
+        store.rollback().unwrap();
+        assert_eq!(names(&store), vec![(1, String::from("a"))]);
+        store.write(vec![insert("a")]).unwrap_err();
+        store.write(vec![insert("c")]).unwrap();
+
+        drop(store);
+        assert_eq!(names(&open(&fixture)), vec![(1, String::from("a")), (2, String::from("c"))]);
+    }
+}
@@ -0,0 +1,108 @@
+use crate::database::backup::BackupError;
+use crate::database::migrations::MigrationError;
+use crate::database::pool::PoolError;
+use crate::database::row::RowError;
+use crate::login::encrypt::CipherError;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error { // What every fallible public API in the crate returns
+    Parse(String), // A malformed request or body
+    Io(std::io::Error),
+    Database(Box<dyn std::error::Error + Send + Sync>), // The backend's own error, kept as the source
+    Auth(AuthError),
+    Config(String), // Settings, keys or addresses the server can't start without
+    TooLarge(u64) // A request over this many bytes
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuthError {
+    Unauthenticated, // No live session, token or key
+    Forbidden, // Signed in but without the role
+    InvalidCode // A TOTP or recovery code that didn't match
+}
+
+impl Error {
+    pub fn database(message: impl Into<String>) -> Self { // For backends without an error type of their own
+        Error::Database(message.into().into())
+    }
+
+    pub fn status(&self) -> (usize, &'static str) { // The response a request failing with this gets
+        match self {
+            Error::Parse(_) => (400, "Bad Request"),
+            Error::TooLarge(_) => (413, "Payload Too Large"),
+            Error::Io(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => (408, "Request Timeout"),
+            Error::Auth(AuthError::Unauthenticated) => (401, "Unauthorized"),
+            Error::Auth(_) => (403, "Forbidden"),
+            Error::Database(e) if matches!(e.downcast_ref::<PoolError>(), Some(PoolError::Timeout(_))) => (503, "Service Unavailable"),
+            _ => (500, "Internal Server Error")
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::Parse(reason) => write!(f, "could not parse request: {}", reason),
+            Error::Io(e) => write!(f, "io error: {}", e),
+            Error::Database(e) => write!(f, "database error: {}", e),
+            Error::Auth(e) => write!(f, "{}", e),
+            Error::Config(reason) => write!(f, "configuration error: {}", reason),
+            Error::TooLarge(limit) => write!(f, "request larger than {} bytes", limit)
+        }
+    }
+}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AuthError::Unauthenticated => write!(f, "not signed in"),
+            AuthError::Forbidden => write!(f, "missing the required role"),
+            AuthError::InvalidCode => write!(f, "invalid code")
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Database(e) => Some(e.as_ref()),
+            Error::Auth(e) => Some(e),
+            Error::Parse(_) | Error::Config(_) | Error::TooLarge(_) => None
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Parse(e.to_string())
+    }
+}
+
+impl From<AuthError> for Error {
+    fn from(e: AuthError) -> Self {
+        Error::Auth(e)
+    }
+}
+
+macro_rules! database_error { // Backend errors keep their type, so callers can downcast the source
+    ($($type:ty),*) => {
+        $(impl From<$type> for Error {
+            fn from(e: $type) -> Self {
+                Error::Database(Box::new(e))
+            }
+        })*
+    };
+}
+
+database_error!(rusqlite::Error, RowError, PoolError, BackupError, MigrationError, CipherError);
@@ -1,21 +1,43 @@
-mod tools;
-mod server;
-mod login;
-mod database;
+pub mod error;
+pub mod tools;
+pub mod server;
+pub mod login;
+pub mod database;
 
 use std::net::{TcpListener, TcpStream, IpAddr, SocketAddr};
 use std::io::prelude::*;
 use local_ip_address::local_ip;
 use tools::config::load_config;
 use std::collections::HashMap;
-use serde::{Deserialize};
-use serde_json;
+use std::cell::OnceCell;
+use std::sync::{Arc, Mutex};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use serde::Deserialize;
 
 use server::response::{Response, ResponseStatus};
+use server::access_log::AccessLog;
+use server::backups::BackupSchedule;
 use tools::filesystem::FileSystem;
-use login::login::Login;
-use login::encrypt::{Keys, Encrypt, Decrypt};
-use database::db::{Database, AQuery, GQuery};
+use tools::config::{get_bool, get_integer, get_string};
+use tools::utils::now;
+use login::login::{Login, LoginResult, PasswordStatus, User};
+use login::register::{Register, RegisterStatus};
+use login::limiter::{RateLimiter, Lockout};
+use login::session::{Session, Challenge};
+use login::totp::{self, Totp};
+use login::keyring::Keyring;
+use login::reset::{Reset, ResetStatus, ResetDelivery, Outbox};
+use login::apikey::{self, ApiKeys};
+use login::csrf::{self, Csrf};
+use login::token::{self, Tokens};
+use login::access::{Access, ADMIN_ROLE};
+use database::access::{AccessEntry, AccessFilter};
+use database::backup::BackupError;
+use database::db::{Database, DatabaseType, AQuery};
+use database::fields;
+use database::schema::Schema;
+use error::{AuthError, Error};
 
 pub enum State {
     Off, 
@@ -31,12 +53,44 @@ enum DatabaseID {
 
 pub struct Server {
     filesystem: FileSystem,
-    databases: HashMap<DatabaseID, Database>,
+    databases: HashMap<DatabaseID, Arc<Database>>,
+    access_log: Option<AccessLog>,
+    _backups: Option<BackupSchedule>, // Held so scheduled backups stop with the server
+    limiter: Mutex<RateLimiter>,
+    access: Access,
+    keyring: Arc<Keyring>,
+    reset_delivery: Box<dyn ResetDelivery>,
+    csrf_exempt: Vec<String>,
     pub ip: IpAddr,
     pub port: u16,
     pub state: State,
 }
 
+const CHALLENGE_SECONDS: u64 = 300;
+const MAX_HEADER_BYTES: usize = 16 * 1024;
+
+#[derive(Clone, Copy)]
+enum Grant { // What a successful sign in hands out
+    Session,
+    Token
+}
+
+impl Grant {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Grant::Session => "session",
+            Grant::Token => "token"
+        }
+    }
+
+    fn from_str(grant: &str) -> Self {
+        match grant {
+            "token" => Grant::Token,
+            _ => Grant::Session
+        }
+    }
+}
+
 #[derive(Deserialize)]
 struct Credentials {
     username: String,
@@ -44,188 +98,978 @@ struct Credentials {
 }
 
 impl Server {
-    pub fn new(ip: IpAddr, port_raw: Option<u16>) -> Self {
+    pub fn new(ip: IpAddr, port_raw: Option<u16>) -> Result<Self, Error> {
         let filesystem = FileSystem::init();
-        let port = match port_raw {
-            Some(num) => num,
-            None => 7878
-        };        
+        let port = port_raw.unwrap_or(7878);
+
+        load_config("config.json")?;
 
-        load_config("config.json");
+        let keyring = Arc::new(Keyring::open(Path::new(&get_string("key_file")), Path::new(&get_string("keyring_file")))?);
+        for name in [totp::KEY_NAME, csrf::KEY_NAME, token::KEY_NAME, fields::KEY_NAME, fields::INDEX_KEY_NAME] {
+            keyring.ensure(name)?;
+        }
+
+        let kind = match DatabaseType::parse(&get_string("database_type")) {
+            Some(kind) => kind,
+            None => {
+                println!("Unknown database_type, using sqlite");
+                DatabaseType::Sqlite
+            }
+        };
+        let login_database = Database::open(kind, Schema::logins(), true)?.with_keyring(keyring.clone())?;
+        let logs_database = Database::open(kind, Schema::logs(), true)?;
+        let fixture = get_string("database_fixture");
+        if kind == DatabaseType::Memory && !fixture.is_empty() { // Persistent databases would get the rows again on every start
+            for (database, src) in [(&login_database, "logins"), (&logs_database, "logs")] {
+                database.seed(Path::new(&fixture), src)?;
+            }
+        }
 
-        let mut databases: HashMap<DatabaseID, Database> = HashMap::new();
-        databases.insert(DatabaseID::Login, Database::connect(true, "logins"));
-        databases.insert(DatabaseID::Logs, Database::connect(true, "logs"));
+        let login_database = Arc::new(login_database);
+        let logs_database = Arc::new(logs_database);
+        let access_log = if get_bool("access_log") {
+            Some(AccessLog::start(
+                logs_database.clone(),
+                get_integer("access_log_batch") as usize,
+                Duration::from_millis(get_integer("access_log_flush_ms")),
+                get_integer("access_log_retention_days") * 86400
+            ))
+        } else {
+            None
+        };
+
+        let backups = match get_integer("backup_interval_hours") {
+            0 => None,
+            hours => Some(BackupSchedule::start(
+                vec![login_database.clone(), logs_database.clone()],
+                PathBuf::from(get_string("backup_dir")),
+                Duration::from_secs(hours * 3600),
+                get_integer("backup_keep") as usize
+            ))
+        };
+
+        let mut databases: HashMap<DatabaseID, Arc<Database>> = HashMap::new();
+        databases.insert(DatabaseID::Login, login_database);
+        databases.insert(DatabaseID::Logs, logs_database);
         println!("Databases {:?}", databases);
 
-        Self {
+        let mut access = Access::new();
+        access.require("unlock", ADMIN_ROLE);
+        access.require("logs", ADMIN_ROLE);
+        access.require("backup", ADMIN_ROLE);
+
+        Ok(Self {
             filesystem,
             databases,
+            access_log,
+            _backups: backups,
+            limiter: Mutex::new(RateLimiter::new(get_integer("max_login_attempts"), get_integer("lockout_seconds"))),
+            access,
+            keyring,
+            reset_delivery: Box::new(Outbox { path: PathBuf::from(get_string("outbox_file")) }),
+            csrf_exempt: Vec::new(),
             ip,
             port,
             state: State::Off
-        }
+        })
     }
 
-    pub fn from_presets() -> Self {
-        let ip_raw = local_ip();
-        let ip = match ip_raw {
-            Ok(ip) => ip,
-            Err(_) => panic!("Failed to load IP Address!"),
-        };
-        let port = Some(7878 as u16);
+    pub fn from_presets() -> Result<Self, Error> {
+        let ip = local_ip().map_err(|e| Error::Config(format!("could not detect the local IP address: {}", e)))?;
+        let port = Some(7878_u16);
         Self::new(ip, port)
     }
 
-    pub fn start(&mut self) {
+    pub fn start(&mut self) -> Result<(), Error> { // Only returns if the address can't be bound
         let addr = SocketAddr::new(self.ip, self.port);
-        self.state = State::Idle;
         println!("Booting up at: \x1b]8;;http://{:?}\x1b\\{:?}\x1b]8;;\x1b\\", addr, addr);
         let listener = 
-            TcpListener::bind(addr).unwrap();
+            TcpListener::bind(addr)?;
+        self.state = State::Idle;
         for stream in listener.incoming() {
-            let stream = stream.unwrap();
-            Self::handle_connection(self, stream);
+            match stream {
+                Ok(stream) => Self::handle_connection(self, stream),
+                Err(e) => println!("Failed to accept connection: {}", e)
+            }
         }
+        Ok(())
     }
 
     fn handle_connection(&mut self, mut stream:TcpStream) {
         self.state = State::Processing;
+        let started = Instant::now();
 
-        let (connection_info, raw_connection) = Self::get_connection_info(&mut stream);
-
+        let (request, _raw_connection) = Self::get_connection_info(&mut stream);
+        let mut lease = None;
         let mut response = Response::new(&self.filesystem);
-        match connection_info.clone() {
-            Some(conn_info) => {
-                if conn_info.r#type == "GET".to_string() {
-                    if conn_info.method == "HTTP" {
-                        let this_conn_str = conn_info.file.as_str();
-                        
-                        if this_conn_str == "" {
-                            response.format_file(
-                                String::from("index.html")
-                            );
-                        } else {
-                            response.format_file(
-                                conn_info.file
-                            );
-                        }
-                    }
-                } else if conn_info.r#type == "POST".to_string() {
-                    let parsed_json: serde_json::Value = serde_json::from_str(&conn_info.body).unwrap();
-                    let this_conn_str = conn_info.file.as_str();
-                    if this_conn_str == "login" {
-                        let username = parsed_json.get("username");
-                        let password = parsed_json.get("password");
-                        if username.is_none() || password.is_none() {
-                            response.format_404();
-                        } else {
-                            let str_username = username.unwrap().as_str().unwrap();
-                            let str_password = password.unwrap().as_str().unwrap();
-                            username.unwrap();
-                            response.format_file(
-                                String::from("index.html")
-                            );
-                            let query = GQuery::Password { username: str_username.to_string() };
-                            let login_database =  self.databases.get(&DatabaseID::Login);
-                            match login_database {
-                                Some(database) => {
-                                    match database.get::<String>(&query) {
-                                        Ok(data) => {
-                                            let mut login_state = false;
-                                            for login in data {
-                                                if str_password == login[0] {
-                                                    login_state = true;
-                                                    break;
-                                                }
-                                                if login_state {break;}
-                                            }
-                                            if login_state {
-                                                response.format_status("ok");
-                                            } else {
-                                                response.format_error(403, "Forbidden");
-                                            }
-        
-                                        },
-                                        Err(e) => {
-                                            println!("Error: {}", e);
-                                            response.format_404();
-                                        }
-                                    }
-                                },
-                                None => response.format_404()
-                            }
+        let handled = match &request {
+            Ok(conn_info) => match self.databases.get(&DatabaseID::Login).map(|database| database.lease()).transpose() {
+                Ok(leased) => {
+                    lease = leased; // One connection for the whole request
+                    self.route(conn_info, &mut response)
+                },
+                Err(e) => Err(e)
+            },
+            Err(_) => Ok(())
+        };
+        if let Some(e) = handled.as_ref().err().or(request.as_ref().err()) {
+            println!("Request failed: {}", e);
+            Self::format_failure(&mut response, e);
+        }
+
+        let connection_info = request.ok();
+        let username = connection_info.as_ref().and_then(|conn_info| conn_info.identity.get().cloned().flatten()); // Only what routing already resolved
+        Self::display_connection(&connection_info, &response, &_raw_connection);
+        if let Err(e) = stream.write_all(response.response_data.as_bytes()).and_then(|_| stream.flush()) {
+            println!("Failed to send response: {}", e);
+        }
+        drop(lease);
+        self.record_access(&connection_info, &response, username, started);
+        self.state = State::Idle;
+    }
+
+    fn route(&self, conn_info: &ConnectionData, response: &mut Response) -> Result<(), Error> {
+        if !self.authorize(conn_info, response)? || !self.verify_csrf(conn_info, response)? {
+            return Ok(());
+        }
+        if conn_info.r#type == "GET" {
+            if conn_info.method == "HTTP" {
+                let this_conn_str = conn_info.file.as_str();
+                let csrf_token = match self.session_token(conn_info)? {
+                    Some(token) => Csrf::token(&self.keyring, &token),
+                    None => String::new()
+                };
+                
+                if this_conn_str.is_empty() {
+                    response.format_template(
+                        String::from("index.html"),
+                        &[(csrf::PLACEHOLDER, &csrf_token)]
+                    );
+                } else {
+                    response.format_template(
+                        conn_info.file.clone(),
+                        &[(csrf::PLACEHOLDER, &csrf_token)]
+                    );
+                }
+            }
+        } else if conn_info.r#type == "POST" {
+            let parsed_json: serde_json::Value = serde_json::from_str(&conn_info.body).unwrap_or_default(); // Form posts carry no JSON
+            let this_conn_str = conn_info.file.as_str();
+            if this_conn_str == "login" {
+                self.handle_login(parsed_json, conn_info.conn_ip, response)?;
+            } else if this_conn_str == "2fa/verify" {
+                self.handle_two_factor(parsed_json, conn_info.conn_ip, response)?;
+            } else if let Some(action) = this_conn_str.strip_prefix("2fa/") {
+                self.handle_two_factor_setup(action, parsed_json, conn_info, response)?;
+            } else if let Some(action) = this_conn_str.strip_prefix("reset/") {
+                self.handle_reset(action, parsed_json, response)?;
+            } else if let Some(action) = this_conn_str.strip_prefix("keys/") {
+                self.handle_keys(action, parsed_json, conn_info, response)?;
+            } else if let Some(view) = this_conn_str.strip_prefix("logs/") {
+                self.handle_logs(view, &parsed_json, response)?;
+            } else if this_conn_str == "backup" {
+                self.handle_backup(response);
+            } else if this_conn_str == "token" {
+                self.handle_token(parsed_json, conn_info.conn_ip, response)?;
+            } else if this_conn_str == "logout" {
+                self.handle_logout(conn_info, response)?;
+            } else if this_conn_str == "unlock" {
+                self.handle_unlock(parsed_json, response)?;
+            } else if this_conn_str == "password" {
+                self.handle_password(parsed_json, conn_info, response)?;
+            } else if this_conn_str == "register" {
+                self.handle_register(&parsed_json, response)?;
+            } else {
+                response.format_404();
+            }
+        } else {
+            response.format_404();
+        }
+        Ok(())
+    }
+
+    fn format_failure(response: &mut Response, error: &Error) { // Replaces whatever the handler had written so far
+        response.headers.clear();
+        match (error, error.status()) {
+            (_, (503, _)) => Self::format_unavailable(response),
+            (Error::Parse(detail), (code, reason)) => response.format_error_detail(code, reason, detail),
+            (_, (code, reason)) => response.format_error(code, reason)
+        }
+    }
+
+    fn format_unavailable(response: &mut Response) { // Every pooled connection stayed busy past database_checkout_timeout_ms
+        response.format_error(503, "Service Unavailable");
+        response.add_header("Retry-After", "1");
+    }
+
+    fn record_access(&self, connection_info: &Option<ConnectionData>, response: &Response, username: Option<String>, started: Instant) {
+        let access_log = match &self.access_log {
+            Some(access_log) => access_log,
+            None => return
+        };
+        let (ip, method, path, user_agent) = match connection_info {
+            Some(conn_info) => (
+                conn_info.conn_ip.map(|addr| addr.ip().to_string()),
+                conn_info.r#type.clone(),
+                format!("/{}", conn_info.file),
+                conn_info.header("User-Agent").map(|agent| agent.to_string())
+            ),
+            None => (None, String::new(), String::new(), None) // Unparseable request
+        };
+        access_log.record(AccessEntry {
+            timestamp: now(),
+            ip,
+            method,
+            path,
+            status: response.status_code(),
+            bytes: response.response_data.len() as u64,
+            duration_ms: started.elapsed().as_millis() as u64,
+            username,
+            user_agent
+        });
+    }
+
+    fn handle_login(&self, parsed_json: serde_json::Value, conn_ip: Option<SocketAddr>, response: &mut Response) -> Result<(), Error> {
+        if let Some(user) = self.sign_in(parsed_json, conn_ip, Grant::Session, response)? {
+            self.grant(Grant::Session, &user.username, response)?;
+        }
+        Ok(())
+    }
+
+    fn handle_token(&self, parsed_json: serde_json::Value, conn_ip: Option<SocketAddr>, response: &mut Response) -> Result<(), Error> {
+        if let Some(user) = self.sign_in(parsed_json, conn_ip, Grant::Token, response)? {
+            self.grant(Grant::Token, &user.username, response)?;
+        }
+        Ok(())
+    }
+
+    fn grant(&self, grant: Grant, username: &str, response: &mut Response) -> Result<(), Error> {
+        let login_database = match self.databases.get(&DatabaseID::Login) {
+            Some(database) => database,
+            None => {
+                response.format_404();
+                return Ok(());
+            }
+        };
+        match grant {
+            Grant::Session => {
+                let session = Session::create(login_database, username, get_integer("session_seconds"))?;
+                response.format_status("ok");
+                response.add_header("Set-Cookie", &session.cookie());
+                response.add_header(csrf::HEADER, &Csrf::token(&self.keyring, &session.token));
+            },
+            Grant::Token => {
+                let lifetime = get_integer("token_seconds");
+                let token = Tokens::issue(login_database, &self.keyring, username, &get_string("token_issuer"), lifetime)?;
+                let body = serde_json::json!({
+                    "access_token": token,
+                    "token_type": "Bearer",
+                    "expires_in": lifetime
+                });
+                response.format_json(&body);
+                response.add_header("Cache-Control", "no-store");
+            }
+        }
+        Ok(())
+    }
+
+    fn sign_in(&self, parsed_json: serde_json::Value, conn_ip: Option<SocketAddr>, grant: Grant, response: &mut Response) -> Result<Option<User>, Error> { // Formats the failure response itself
+        let credentials = serde_json::from_value::<Credentials>(parsed_json)?;
+        let ip = match conn_ip {
+            Some(addr) => addr.ip().to_string(),
+            None => String::from("unknown")
+        };
+        let mut limiter = self.limiter.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(wait) = limiter.retry_after(&ip, &credentials.username) {
+            response.format_error(429, "Too Many Requests");
+            response.add_header("Retry-After", &wait.to_string());
+            return Ok(None);
+        }
+
+        let login_database = match self.databases.get(&DatabaseID::Login) {
+            Some(database) => database,
+            None => {
+                response.format_404();
+                return Ok(None);
+            }
+        };
+        let username = credentials.username.clone();
+        match Login::new(credentials.username, credentials.password).attempt(login_database)? {
+            LoginResult::Authenticated(user) => {
+                limiter.clear(&username);
+                return Ok(Some(user));
+            },
+            LoginResult::TwoFactorRequired(user) => {
+                let challenge = Challenge::create(login_database, &user.username, grant.as_str(), CHALLENGE_SECONDS)?;
+                response.format_json(&serde_json::json!({
+                    "status": "2fa_required",
+                    "challenge": challenge
+                }));
+            },
+            LoginResult::BadCredentials => {
+                self.record_failure(&mut limiter, login_database, &ip, &username)?;
+                response.format_error(403, "Forbidden");
+            },
+            LoginResult::Locked => Self::format_locked(login_database, &username, response)?,
+            LoginResult::Disabled => response.format_error_detail(403, "Forbidden", "Account disabled")
+        }
+        Ok(None)
+    }
+
+    fn format_locked(login_database: &Database, username: &str, response: &mut Response) -> Result<(), Error> {
+        match Login::retry_after(login_database, username)? {
+            Some(wait) => {
+                response.format_error(429, "Too Many Requests");
+                response.add_header("Retry-After", &wait.to_string());
+            },
+            None => response.format_error_detail(403, "Forbidden", "Account locked")
+        }
+        Ok(())
+    }
+
+    fn record_failure(&self, limiter: &mut RateLimiter, login_database: &Database, ip: &str, username: &str) -> Result<(), Error> {
+        for lockout in limiter.fail(ip, username) {
+            match lockout {
+                Lockout::Account(username) => {
+                    Login::lock(login_database, &username, get_integer("lockout_seconds"))?;
+                    self.log_event("account_locked", Some(ip), Some(&username), None);
+                },
+                Lockout::Address(ip) => {
+                    self.log_event("address_blocked", Some(&ip), Some(username), None);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_two_factor(&self, parsed_json: serde_json::Value, conn_ip: Option<SocketAddr>, response: &mut Response) -> Result<(), Error> {
+        let (challenge, code) = match (
+            parsed_json.get("challenge").and_then(|value| value.as_str()),
+            parsed_json.get("code").and_then(|value| value.as_str())
+        ) {
+            (Some(challenge), Some(code)) => (challenge, code),
+            _ => {
+                response.format_error_detail(400, "Bad Request", "Missing challenge or code");
+                return Ok(());
+            }
+        };
+        let login_database = match self.databases.get(&DatabaseID::Login) {
+            Some(database) => database,
+            None => {
+                response.format_404();
+                return Ok(());
+            }
+        };
+        let (username, grant) = match Challenge::find(login_database, challenge)? {
+            Some(found) => found,
+            None => {
+                response.format_error(403, "Forbidden");
+                return Ok(());
+            }
+        };
+        let ip = match conn_ip {
+            Some(addr) => addr.ip().to_string(),
+            None => String::from("unknown")
+        };
+
+        let mut limiter = self.limiter.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(wait) = limiter.retry_after(&ip, &username) {
+            response.format_error(429, "Too Many Requests");
+            response.add_header("Retry-After", &wait.to_string());
+            return Ok(());
+        }
+        if Totp::verify(login_database, &self.keyring, &username, code)? {
+            limiter.clear(&username);
+            Challenge::end(login_database, challenge)?;
+            self.grant(Grant::from_str(&grant), &username, response)?;
+        } else {
+            self.record_failure(&mut limiter, login_database, &ip, &username)?;
+            response.format_error(403, "Forbidden");
+        }
+        Ok(())
+    }
+
+    fn handle_two_factor_setup(&self, action: &str, parsed_json: serde_json::Value, conn_info: &ConnectionData, response: &mut Response) -> Result<(), Error> {
+        let username = self.authenticate(conn_info)?.ok_or(AuthError::Unauthenticated)?;
+        let login_database = match self.databases.get(&DatabaseID::Login) {
+            Some(database) => database,
+            None => {
+                response.format_404();
+                return Ok(());
+            }
+        };
+        let code = parsed_json.get("code").and_then(|value| value.as_str()).unwrap_or_default();
+
+        match action {
+            "enroll" => {
+                if Totp::enabled(login_database, &username)? {
+                    response.format_error_detail(409, "Conflict", "Two factor authentication already enabled");
+                    return Ok(());
+                }
+                let enrolment = Totp::enroll(login_database, &self.keyring, &username, &get_string("token_issuer"))?;
+                response.format_json(&serde_json::json!({
+                    "secret": enrolment.secret,
+                    "uri": enrolment.uri
+                }));
+            },
+            "confirm" => {
+                let recovery_codes = Totp::confirm(login_database, &self.keyring, &username, code)?;
+                self.log_event("2fa_enabled", None, Some(&username), None);
+                response.format_json(&serde_json::json!({ "recovery_codes": recovery_codes }));
+            },
+            "disable" => {
+                if !Totp::verify(login_database, &self.keyring, &username, code)? {
+                    return Err(AuthError::InvalidCode.into());
+                }
+                Totp::disable(login_database, &username)?;
+                self.log_event("2fa_disabled", None, Some(&username), None);
+                response.format_status("ok");
+            },
+            _ => response.format_404()
+        }
+        Ok(())
+    }
+
+    fn handle_reset(&self, action: &str, parsed_json: serde_json::Value, response: &mut Response) -> Result<(), Error> {
+        let login_database = match self.databases.get(&DatabaseID::Login) {
+            Some(database) => database,
+            None => {
+                response.format_404();
+                return Ok(());
+            }
+        };
+        match action {
+            "request" => {
+                let identifier = parsed_json
+                    .get("username")
+                    .or_else(|| parsed_json.get("email"))
+                    .and_then(|value| value.as_str());
+                match identifier {
+                    Some(identifier) => {
+                        match Reset::request(login_database, self.reset_delivery.as_ref(), identifier, get_integer("reset_seconds")) {
+                            Ok(true) => self.log_event("reset_requested", None, Some(identifier), None),
+                            Ok(false) => {},
+                            Err(e) => println!("Failed to send a password reset: {}", e)
                         }
-                    } else{
-                        response.format_404();
+                        response.format_status("ok"); // Same answer either way so accounts can't be probed
+                    },
+                    None => response.format_error_detail(400, "Bad Request", "Missing username or email")
+                }
+            },
+            "confirm" => {
+                let (token, password) = match (
+                    parsed_json.get("token").and_then(|value| value.as_str()),
+                    parsed_json.get("password").and_then(|value| value.as_str())
+                ) {
+                    (Some(token), Some(password)) => (token, password),
+                    _ => {
+                        response.format_error_detail(400, "Bad Request", "Missing token or password");
+                        return Ok(());
                     }
+                };
+                match Reset::confirm(login_database, token, password)? {
+                    ResetStatus::Reset(username) => {
+                        self.log_event("password_reset", None, Some(&username), None);
+                        response.format_status("ok");
+                    },
+                    ResetStatus::InvalidToken => response.format_error(403, "Forbidden"),
+                    ResetStatus::Invalid(reason) => response.format_error_detail(400, "Bad Request", reason)
+                }
+            },
+            _ => response.format_404()
+        }
+        Ok(())
+    }
+
+    pub fn set_reset_delivery(&mut self, delivery: Box<dyn ResetDelivery>) { // Replaces the default outbox file
+        self.reset_delivery = delivery;
+    }
+
+    fn handle_logs(&self, view: &str, parsed_json: &serde_json::Value, response: &mut Response) -> Result<(), Error> { // Admin only, see Access::require in new
+        let logs_database = match self.databases.get(&DatabaseID::Logs) {
+            Some(database) => database,
+            None => {
+                response.format_404();
+                return Ok(());
+            }
+        };
+        let _lease = logs_database.lease()?; // The access log writer shares this pool
+        let filter = AccessFilter::from_json(parsed_json);
+        let integer = |key: &str, default: i64| parsed_json.get(key).and_then(|value| value.as_i64()).unwrap_or(default);
+
+        let body = match view {
+            "entries" => serde_json::json!(logs_database.access_entries(&filter, integer("limit", 100), integer("offset", 0))?),
+            "per_minute" => serde_json::json!(logs_database.access_per_minute(&filter)?),
+            "top_paths" => serde_json::json!(logs_database.access_top_paths(&filter, integer("limit", 10))?),
+            "summary" => serde_json::json!(logs_database.access_summary(&filter)?),
+            _ => {
+                response.format_404();
+                return Ok(());
+            }
+        };
+        response.format_json(&body);
+        Ok(())
+    }
+
+    fn handle_backup(&self, response: &mut Response) { // Admin only, see Access::require in new
+        match self.backup_databases() {
+            Ok(paths) => {
+                let paths: Vec<String> = paths.iter().map(|path| path.display().to_string()).collect();
+                response.format_json(&serde_json::json!({ "backups": paths }));
+            },
+            Err(e) => {
+                println!("Failed to back up: {}", e);
+                match e {
+                    BackupError::Unsupported(_) => response.format_error_detail(501, "Not Implemented", &e.to_string()),
+                    BackupError::Unavailable(_) => Self::format_unavailable(response),
+                    _ => response.format_error(500, "Internal Server Error")
+                }
+            }
+        }
+    }
+
+    fn handle_keys(&self, action: &str, parsed_json: serde_json::Value, conn_info: &ConnectionData, response: &mut Response) -> Result<(), Error> {
+        if conn_info.header(apikey::HEADER).is_some() {
+            response.format_error_detail(403, "Forbidden", "API keys cannot manage API keys");
+            return Ok(());
+        }
+        let username = self.authenticate(conn_info)?.ok_or(AuthError::Unauthenticated)?;
+        let login_database = match self.databases.get(&DatabaseID::Login) {
+            Some(database) => database,
+            None => {
+                response.format_404();
+                return Ok(());
+            }
+        };
+
+        match action {
+            "create" => {
+                let label = parsed_json.get("label").and_then(|value| value.as_str()).unwrap_or_default();
+                let scopes: Vec<String> = match parsed_json.get("scopes").and_then(|value| value.as_array()) {
+                    Some(scopes) => scopes
+                        .iter()
+                        .filter_map(|scope| scope.as_str().map(|s| s.to_string()))
+                        .collect(),
+                    None => Vec::new()
+                };
+                let (id, key) = ApiKeys::create(login_database, &username, label, &scopes)?;
+                self.log_event("api_key_created", None, Some(&username), Some(&id));
+                response.format_json(&serde_json::json!({ "id": id, "key": key }));
+                response.add_header("Cache-Control", "no-store");
+            },
+            "list" => {
+                response.format_json(&serde_json::json!(ApiKeys::list(login_database, &username)?));
+            },
+            "revoke" => {
+                match parsed_json.get("id").and_then(|value| value.as_str()) {
+                    Some(id) if ApiKeys::revoke(login_database, &username, id)? => {
+                        self.log_event("api_key_revoked", None, Some(&username), Some(id));
+                        response.format_status("ok");
+                    },
+                    Some(_) => response.format_404(),
+                    None => response.format_error_detail(400, "Bad Request", "Missing id")
+                }
+            },
+            _ => response.format_404()
+        }
+        Ok(())
+    }
+
+    fn handle_logout(&self, conn_info: &ConnectionData, response: &mut Response) -> Result<(), Error> {
+        if let (Some(token), Some(database)) = (
+            conn_info.header("Cookie").and_then(Session::from_cookie),
+            self.databases.get(&DatabaseID::Login)
+        ) {
+            Session::end(database, &token)?;
+        }
+        response.format_status("ok");
+        response.add_header("Set-Cookie", &Session::expired_cookie());
+        Ok(())
+    }
+
+    fn handle_unlock(&self, parsed_json: serde_json::Value, response: &mut Response) -> Result<(), Error> {
+        match parsed_json.get("username").and_then(|value| value.as_str()) {
+            Some(username) => {
+                self.unlock_account(username)?;
+                response.format_status("ok");
+            },
+            None => response.format_error_detail(400, "Bad Request", "Missing username")
+        }
+        Ok(())
+    }
+
+    pub fn require_role(&mut self, path: &str, role: &str) { // Marks a route or public/ subpath as needing a role
+        self.access.require(path, role);
+    }
+
+    pub fn rotate_signing_key(&self) -> Result<String, Error> { // -> the new key's kid
+        let database = self.databases.get(&DatabaseID::Login).ok_or_else(|| Error::Config(String::from("no logins database")))?;
+        let kid = Tokens::rotate(database, &self.keyring)?;
+        self.log_event("signing_key_rotated", None, None, Some(&kid));
+        Ok(kid)
+    }
+
+    pub fn rotate_data_key(&self, name: &str) -> Result<String, Error> { // -> the new key's ID, existing ciphertext is re-encrypted as it is next read or written
+        if name == fields::INDEX_KEY_NAME {
+            return Err(Error::Config(format!("refusing to rotate {}, stored blind indexes would no longer match", name)));
+        }
+        let id = self.keyring.rotate(name)?;
+        self.log_event("data_key_rotated", None, None, Some(&id));
+        Ok(id)
+    }
+
+    pub fn backup_databases(&self) -> Result<Vec<PathBuf>, BackupError> { // On demand, into backup_dir with the same retention as the schedule
+        let dir = PathBuf::from(get_string("backup_dir"));
+        let keep = get_integer("backup_keep") as usize;
+        let mut paths = Vec::new();
+        for id in [DatabaseID::Login, DatabaseID::Logs] {
+            if let Some(database) = self.databases.get(&id) {
+                paths.push(database.backup(&dir, keep)?);
+            }
+        }
+        Ok(paths)
+    }
+
+    pub fn restore_database(&self, name: &str, source: &Path) -> Result<(), BackupError> { // name as in the backup file, logins or logs
+        let database = self.databases
+            .values()
+            .find(|database| database.name == name)
+            .ok_or_else(|| BackupError::NotFound(name.to_string()))?;
+        database.restore(source)?;
+        self.log_event("database_restored", None, None, Some(&format!("{} from {}", name, source.display())));
+        Ok(())
+    }
+
+    pub fn csrf_exempt(&mut self, path: &str) { // For routes only ever called with bearer tokens or API keys
+        self.csrf_exempt.push(path.trim_matches('/').to_string());
+    }
+
+    fn session_token(&self, conn_info: &ConnectionData) -> Result<Option<String>, Error> { // Only returns tokens of live sessions
+        let (database, token) = match (self.databases.get(&DatabaseID::Login), conn_info.header("Cookie").and_then(Session::from_cookie)) {
+            (Some(database), Some(token)) => (database, token),
+            _ => return Ok(None)
+        };
+        let owner = Session::find(database, &token)?;
+        if conn_info.header(apikey::HEADER).is_none() && conn_info.header("Authorization").and_then(Tokens::from_header).is_none() { // The cookie is what authenticate would use too
+            conn_info.identity.get_or_init(|| owner.clone());
+        }
+        Ok(owner.map(|_| token))
+    }
+
+    fn verify_csrf(&self, conn_info: &ConnectionData, response: &mut Response) -> Result<bool, Error> {
+        if matches!(conn_info.r#type.as_str(), "GET" | "HEAD" | "OPTIONS")
+            || self.csrf_exempt.iter().any(|path| Access::matches(path, &conn_info.file)) {
+            return Ok(true);
+        }
+
+        let source = conn_info.header("Origin").or_else(|| conn_info.header("Referer"));
+        if let (Some(source), Some(host)) = (source, conn_info.header("Host")) {
+            if !Csrf::same_origin(source, host) {
+                response.format_error_detail(403, "Forbidden", "Cross-site request rejected");
+                return Ok(false);
+            }
+        }
+
+        let session_token = match self.session_token(conn_info)? {
+            Some(token) => token,
+            None => return Ok(true) // Nothing ambient for a forged request to ride on
+        };
+        let presented = match conn_info.header(csrf::HEADER) {
+            Some(token) => Some(token.to_string()),
+            None => serde_json::from_str::<serde_json::Value>(&conn_info.body)
+                .ok()
+                .and_then(|json| json.get(csrf::FIELD).and_then(|value| value.as_str()).map(|s| s.to_string()))
+                .or_else(|| Csrf::from_form(&conn_info.body))
+        };
+        match presented {
+            Some(token) if Csrf::verify(&self.keyring, &session_token, &token) => Ok(true),
+            _ => {
+                response.format_error_detail(403, "Forbidden", "Missing or invalid CSRF token");
+                Ok(false)
+            }
+        }
+    }
+
+    fn authenticate(&self, conn_info: &ConnectionData) -> Result<Option<String>, Error> { // Resolved once per request, later calls reuse it
+        if let Some(identity) = conn_info.identity.get() {
+            return Ok(identity.clone());
+        }
+        let identity = self.resolve_identity(conn_info)?;
+        Ok(conn_info.identity.get_or_init(|| identity).clone())
+    }
+
+    fn resolve_identity(&self, conn_info: &ConnectionData) -> Result<Option<String>, Error> {
+        let database = match self.databases.get(&DatabaseID::Login) {
+            Some(database) => database,
+            None => return Ok(None)
+        };
+        if let Some(key) = conn_info.header(apikey::HEADER) {
+            return ApiKeys::authenticate(database, key, &conn_info.file);
+        }
+        if let Some(token) = conn_info.header("Authorization").and_then(Tokens::from_header) {
+            return Ok(Tokens::verify(database, &self.keyring, token, &get_string("token_issuer"), get_integer("token_seconds"))?
+                .map(|claims| claims.sub));
+        }
+        match conn_info.header("Cookie").and_then(Session::from_cookie) {
+            Some(token) => Session::find(database, &token),
+            None => Ok(None)
+        }
+    }
+
+    fn authorize(&self, conn_info: &ConnectionData, response: &mut Response) -> Result<bool, Error> {
+        let path = match conn_info.file.as_str() {
+            "" => "index.html",
+            file => file
+        };
+        let role = match self.access.required_role(path) {
+            Some(role) => role,
+            None => return Ok(true)
+        };
+        let username = match self.authenticate(conn_info)? {
+            Some(username) => username,
+            None => {
+                let browser = conn_info.header("Accept").is_some_and(|accept| accept.contains("text/html"));
+                if browser {
+                    response.format_login_redirect(&format!("/{}", get_string("login_page")));
+                } else {
+                    response.format_error(401, "Unauthorized");
+                }
+                return Ok(false);
+            }
+        };
+        let allowed = match self.databases.get(&DatabaseID::Login) {
+            Some(database) => Access::has_role(database, &username, role)?,
+            None => false
+        };
+        if !allowed {
+            response.format_error(403, "Forbidden");
+        }
+        Ok(allowed)
+    }
+
+    pub fn unlock_account(&self, username: &str) -> Result<(), Error> {
+        self.limiter.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clear(username);
+        if let Some(database) = self.databases.get(&DatabaseID::Login) {
+            Login::unlock(database, username)?;
+        }
+        self.log_event("account_unlocked", None, Some(username), None);
+        Ok(())
+    }
+
+    fn log_event(&self, kind: &str, ip: Option<&str>, username: Option<&str>, detail: Option<&str>) { // Best effort, a lost event never fails the request
+        if let Some(database) = self.databases.get(&DatabaseID::Logs) {
+            let event = AQuery::Event {
+                timestamp: now() as i64,
+                kind: kind.to_string(),
+                ip: ip.map(|s| s.to_string()),
+                username: username.map(|s| s.to_string()),
+                detail: detail.map(|s| s.to_string())
+            };
+            if let Err(e) = database.add(&event) {
+                println!("Failed to log {} event: {}", kind, e);
+            }
+        }
+    }
+
+    fn handle_password(&self, parsed_json: serde_json::Value, conn_info: &ConnectionData, response: &mut Response) -> Result<(), Error> {
+        let new_password = match parsed_json.get("new_password").and_then(|value| value.as_str()) {
+            Some(new_password) => new_password.to_string(),
+            None => {
+                response.format_error_detail(400, "Bad Request", "Missing new_password");
+                return Ok(());
+            }
+        };
+        let code = parsed_json.get("code").and_then(|value| value.as_str()).map(|code| code.to_string()); // TOTP or recovery code, for accounts with 2FA
+        let credentials = serde_json::from_value::<Credentials>(parsed_json)?;
+        let login_database = match self.databases.get(&DatabaseID::Login) {
+            Some(database) => database,
+            None => {
+                response.format_404();
+                return Ok(());
+            }
+        };
+        let ip = match conn_info.conn_ip {
+            Some(addr) => addr.ip().to_string(),
+            None => String::from("unknown")
+        };
+        let mut limiter = self.limiter.lock().unwrap_or_else(|poisoned| poisoned.into_inner()); // Same budget as signing in, so this can't be used to guess passwords
+        if let Some(wait) = limiter.retry_after(&ip, &credentials.username) {
+            response.format_error(429, "Too Many Requests");
+            response.add_header("Retry-After", &wait.to_string());
+            return Ok(());
+        }
+
+        let username = credentials.username.clone();
+        let signed_in = match conn_info.header("Cookie").and_then(Session::from_cookie) { // This browser gets a fresh session once the old ones end
+            Some(token) => Session::find(login_database, &token)?.is_some_and(|owner| owner == username),
+            None => false
+        };
+        match Login::new(credentials.username, credentials.password).change_password(login_database, &self.keyring, &new_password, code.as_deref())? {
+            PasswordStatus::Changed => {
+                limiter.clear(&username);
+                self.log_event("password_changed", Some(&ip), Some(&username), None);
+                if signed_in {
+                    self.grant(Grant::Session, &username, response)?;
                 } else {
-                    response.format_404();
+                    response.format_status("ok");
                 }
             },
+            PasswordStatus::Rejected(LoginResult::TwoFactorRequired(_)) if code.is_none() => {
+                response.format_error_detail(403, "Forbidden", "Two factor code required");
+            },
+            PasswordStatus::Rejected(LoginResult::BadCredentials | LoginResult::TwoFactorRequired(_)) => {
+                self.record_failure(&mut limiter, login_database, &ip, &username)?;
+                response.format_error(403, "Forbidden");
+            },
+            PasswordStatus::Rejected(LoginResult::Locked) => Self::format_locked(login_database, &username, response)?,
+            PasswordStatus::Rejected(_) => response.format_error(403, "Forbidden"),
+            PasswordStatus::Invalid(reason) => response.format_error_detail(400, "Bad Request", reason)
+        }
+        Ok(())
+    }
+
+    fn handle_register(&self, parsed_json: &serde_json::Value, response: &mut Response) -> Result<(), Error> {
+        if !get_bool("registration") {
+            response.format_error(403, "Forbidden");
+            return Ok(());
+        }
+        let register = match Register::from_json(parsed_json) {
+            Some(register) => register,
+            None => {
+                response.format_error_detail(400, "Bad Request", "Missing username, password or email");
+                return Ok(());
+            }
+        };
+        let login_database = match self.databases.get(&DatabaseID::Login) {
+            Some(database) => database,
             None => {
                 response.format_404();
+                return Ok(());
             }
+        };
+        match register.submit(login_database)? {
+            RegisterStatus::Created => response.format_status("ok"),
+            RegisterStatus::Invalid(reason) => response.format_error_detail(400, "Bad Request", reason),
+            RegisterStatus::Conflict(reason) => response.format_error_detail(409, "Conflict", reason)
         }
+        Ok(())
+    }
 
-        Self::display_connection(&connection_info, &response, &raw_connection);
-        stream.write(response.response_data.as_bytes()).unwrap();
-        stream.flush().unwrap();
-        self.state = State::Idle;
+    fn read_request(stream: &mut TcpStream) -> Result<String, Error> { // Up to the blank line after the headers, then exactly Content-Length bytes
+        let limit = get_integer("max_request_bytes");
+        let mut data = Vec::new();
+        let mut buffer = [0; 4096];
+        let header_end = loop {
+            if let Some(end) = Self::header_end(&data) {
+                break end;
+            }
+            if data.len() > MAX_HEADER_BYTES {
+                return Err(Error::TooLarge(MAX_HEADER_BYTES as u64));
+            }
+            let read = stream.read(&mut buffer)?;
+            if read == 0 {
+                break data.len(); // Closed early, parsed as far as it got
+            }
+            data.extend_from_slice(&buffer[..read]);
+        };
+
+        let length = String::from_utf8_lossy(&data[..header_end])
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("Content-Length"))
+            .map(|(_, value)| value.trim().parse::<u64>().map_err(|_| Error::Parse(String::from("Invalid Content-Length"))))
+            .transpose()?
+            .unwrap_or(0);
+        if length > limit {
+            return Err(Error::TooLarge(limit));
+        }
+        let total = header_end + length as usize;
+        while data.len() < total {
+            let read = stream.read(&mut buffer)?;
+            if read == 0 {
+                return Err(Error::Parse(String::from("Body shorter than Content-Length")));
+            }
+            data.extend_from_slice(&buffer[..read]);
+        }
+        data.truncate(total);
+        Ok(String::from_utf8_lossy(&data).to_string())
     }
 
-    fn get_connection_info(stream: &mut TcpStream) -> (Option<ConnectionData>, Option<Vec<String>>) {
-        let mut buffer = [0; 1024];
-        let bytes_read = stream.read(&mut buffer).unwrap();
+    fn header_end(data: &[u8]) -> Option<usize> { // Just past the blank line, bare newlines accepted too
+        let crlf = data.windows(4).position(|window| window == b"\r\n\r\n").map(|i| i + 4);
+        let lf = data.windows(2).position(|window| window == b"\n\n").map(|i| i + 2);
+        crlf.into_iter().chain(lf).min()
+    }
 
-        let binding = 
-            String::from_utf8_lossy(&buffer[..bytes_read])
-                .to_string();
+    fn get_connection_info(stream: &mut TcpStream) -> (Result<ConnectionData, Error>, Option<Vec<String>>) {
+        let timeout = Some(Duration::from_millis(get_integer("request_timeout_ms").max(1))); // A client that stalls can't hold the server
+        if let Err(e) = stream.set_read_timeout(timeout).and_then(|_| stream.set_write_timeout(timeout)) {
+            return (Err(Error::Io(e)), None);
+        }
+        let binding = match Self::read_request(stream) {
+            Ok(request) => request,
+            Err(e) => return (Err(e), None)
+        };
         let request_details: Vec<String> = binding
             .lines()
             .map(|s| s.to_string())
             .collect();
         
-        if request_details.len() <= 0 {
-            return (None, None);
+        if request_details.is_empty() {
+            return (Err(Error::Parse(String::from("Empty request"))), None);
         }
 
-        let request_type: Vec<String> = request_details[0]
-            .split('/')
-            .map(|s| s.to_string())
-            .collect(); 
+        let request_line: Vec<&str> = request_details[0]
+            .split_whitespace()
+            .collect();
         
-        if request_type.len() <= 0 {
-            return (None, None);
+        if request_line.len() < 3 {
+            return (Err(Error::Parse(String::from("Malformed request line"))), Some(request_details));
         }
 
-        let request_file: Vec<String> = request_type[1]
-            .split(' ')
-            .map(|s| s.trim().to_string())
-            .collect();
-    
-        let this_ip = match stream.local_addr() {
-            Ok(ip) => Some(ip),
-            Err(_) => None
+        let target = request_line[1]
+            .split('?')
+            .next()
+            .unwrap_or_default();
+        let file = match FileSystem::normalize(target) {
+            Some(file) => file,
+            None => return (Err(Error::Parse(String::from("Invalid path"))), Some(request_details))
         };
+        let this_ip = stream.peer_addr().ok();
 
-        let body: String = match request_details.last() {
-            Some(body) => {body.clone()}
-            None => "".to_string()
-        };
+        let mut headers: HashMap<String, String> = HashMap::new();
+        let mut lines = request_details.iter().skip(1);
+        for line in lines.by_ref() {
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+            }
+        }
+        let body: String = lines
+            .map(|line| line.as_str())
+            .collect::<Vec<&str>>()
+            .join("\n");
 
         let connection_info = ConnectionData {
-            r#type: request_type[0].trim().to_string(),
-            file: request_file[0].trim().to_string(),
-            method: request_file[1].trim().to_string(),
+            r#type: request_line[0].to_string(),
+            file,
+            method: request_line[2]
+                .split('/')
+                .next()
+                .unwrap_or_default()
+                .to_string(),
             conn_ip: this_ip,
-            body: body
+            headers,
+            body,
+            identity: OnceCell::new()
         };
 
-        (Some(connection_info), Some(request_details))
+        (Ok(connection_info), Some(request_details))
     }
 
-    fn display_connection(connection_info: &Option<ConnectionData>, response: &Response, raw_connection: &Option<Vec<String>>) {
+    fn display_connection(connection_info: &Option<ConnectionData>, response: &Response, _raw_connection: &Option<Vec<String>>) {
         let conn_color = match response.response_status {
             ResponseStatus::Ok => "\x1b[32m",
             ResponseStatus::Failed => "\x1b[33m",
@@ -234,8 +1078,8 @@ impl Server {
         };
         match connection_info {
             Some(conn_info) => {
-                let addr = match &conn_info.file.as_str() {
-                    &"" => String::from("/"),
+                let addr = match conn_info.file.as_str() {
+                    "" => String::from("/"),
                     _ => {conn_info.file.clone()}
                 };
                 match conn_info.conn_ip {
@@ -254,5 +1098,13 @@ pub struct ConnectionData {
     pub file: String,
     pub method: String,
     pub conn_ip: Option<SocketAddr>,
-    pub body: String
+    pub headers: HashMap<String, String>, // Names are lowercased
+    pub body: String,
+    identity: OnceCell<Option<String>> // Username behind the request's key, token or session, once something has asked
+}
+
+impl ConnectionData {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_ascii_lowercase()).map(|value| value.as_str())
+    }
 }
\ No newline at end of file
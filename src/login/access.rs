@@ -0,0 +1,64 @@
+use crate::database::db::{Database, AQuery, GQuery};
+use crate::error::Error;
+
+pub const ADMIN_ROLE: &str = "admin";
+pub const DEFAULT_ROLE: &str = "user";
+
+pub struct Access {
+    rules: Vec<(String, String)> // path prefix, required role
+}
+
+impl Access {
+    pub fn new() -> Self {
+        Self {
+            rules: Vec::new()
+        }
+    }
+
+    pub fn require(&mut self, path: &str, role: &str) {
+        let path = path.trim_matches('/').to_string();
+        self.rules.retain(|(prefix, _)| *prefix != path);
+        self.rules.push((path, role.to_string()));
+    }
+
+    pub fn required_role(&self, path: &str) -> Option<&str> { // Longest matching prefix wins
+        let path = path.trim_matches('/');
+        self.rules
+            .iter()
+            .filter(|(prefix, _)| Self::matches(prefix, path))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, role)| role.as_str())
+    }
+
+    pub fn matches(prefix: &str, path: &str) -> bool { // Prefixes only match whole path segments
+        let (prefix, path) = (prefix.trim_matches('/'), path.trim_matches('/'));
+        prefix.is_empty()
+            || path == prefix
+            || path.strip_prefix(prefix).is_some_and(|rest| rest.starts_with('/'))
+    }
+
+    pub fn roles(database: &Database, username: &str) -> Result<Vec<String>, Error> {
+        let rows = database.get::<String>(&GQuery::Roles { username: username.to_string() })?;
+        Ok(rows.into_iter().filter_map(|row| row.into_iter().next()).collect())
+    }
+
+    pub fn has_role(database: &Database, username: &str, role: &str) -> Result<bool, Error> { // Admins pass every check
+        Ok(Self::roles(database, username)?
+            .iter()
+            .any(|held| held == role || held == ADMIN_ROLE))
+    }
+
+    pub fn grant(database: &Database, username: &str, role: &str) -> Result<(), Error> {
+        database.add(&AQuery::Role { username: username.to_string(), role: role.to_string() })
+    }
+
+    pub fn revoke(database: &Database, username: &str, role: &str) -> Result<(), Error> {
+        database.add(&AQuery::RevokeRole { username: username.to_string(), role: role.to_string() })
+    }
+}
+
+impl Default for Access {
+    fn default() -> Self {
+        Self::new()
+    }
+}
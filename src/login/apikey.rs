@@ -0,0 +1,98 @@
+use serde::Serialize;
+
+use crate::database::db::{Database, AQuery, GQuery};
+use crate::database::row::{FromRow, Row, RowError};
+use crate::error::Error;
+use crate::login::access::Access;
+use crate::login::encrypt::{Encrypt, Keys};
+use crate::tools::utils::now;
+
+pub const HEADER: &str = "X-API-Key";
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ApiKey {
+    pub id: String,
+    pub label: String,
+    pub scopes: Vec<String>, // Route prefixes, empty allows every route
+    pub created: u64,
+    pub last_used: Option<u64>,
+    pub revoked: bool
+}
+
+impl FromRow for ApiKey {
+    fn from_row(row: &Row) -> Result<Self, RowError> {
+        Ok(Self {
+            id: row.get("id")?,
+            label: row.get::<Option<String>>("label")?.unwrap_or_default(),
+            scopes: ApiKeys::scopes(&row.get::<String>("scopes")?),
+            created: row.get::<i64>("created")?.max(0) as u64,
+            last_used: row.get::<Option<i64>>("last_used")?.map(|used| used.max(0) as u64),
+            revoked: row.get::<i64>("revoked")? != 0
+        })
+    }
+}
+
+pub struct ApiKeys; // Long lived machine credentials, only their SHA-256 hash is stored
+
+impl ApiKeys {
+    pub fn create(database: &Database, username: &str, label: &str, scopes: &[String]) -> Result<(String, String), Error> { // -> (id, plaintext key)
+        let id = Keys::token()[..12].to_string();
+        let key = format!("{}.{}", id, Keys::token());
+        let query = AQuery::ApiKey {
+            id: id.clone(),
+            username: username.to_string(),
+            label: label.to_string(),
+            hash: Encrypt::sha256(&key),
+            scopes: scopes
+                .iter()
+                .map(|scope| scope.trim_matches('/'))
+                .collect::<Vec<&str>>()
+                .join(","),
+            created: now() as i64
+        };
+        database.add(&query)?;
+        Ok((id, key))
+    }
+
+    pub fn list(database: &Database, username: &str) -> Result<Vec<ApiKey>, Error> {
+        database.fetch::<ApiKey>(&GQuery::ApiKeys { username: username.to_string() })
+    }
+
+    pub fn revoke(database: &Database, username: &str, id: &str) -> Result<bool, Error> { // false when the user has no such live key
+        database.transaction(|database| { // The ownership check and the revoke see the same keys
+            let owned = Self::list(database, username)?
+                .iter()
+                .any(|key| key.id == id && !key.revoked);
+            if owned {
+                database.add(&AQuery::RevokeApiKey { id: id.to_string(), username: username.to_string() })?;
+            }
+            Ok(owned)
+        })
+    }
+
+    pub fn authenticate(database: &Database, key: &str, path: &str) -> Result<Option<String>, Error> { // -> owner, when the key is live and scoped to path
+        let hash = Encrypt::sha256(key.trim());
+        let rows = database.get::<String>(&GQuery::ApiKey { hash: hash.clone() })?;
+        let (username, scopes) = match rows.into_iter().next().map(|row| row.into_iter()) {
+            Some(mut row) => match (row.next(), row.next()) {
+                (Some(username), Some(scopes)) => (username, Self::scopes(&scopes)),
+                _ => return Ok(None)
+            },
+            None => return Ok(None)
+        };
+
+        if !scopes.is_empty() && !scopes.iter().any(|scope| Access::matches(scope, path)) {
+            return Ok(None);
+        }
+        database.add(&AQuery::ApiKeyUsed { hash, used: now() as i64 })?;
+        Ok(Some(username))
+    }
+
+    fn scopes(joined: &str) -> Vec<String> {
+        joined
+            .split(',')
+            .filter(|scope| !scope.is_empty())
+            .map(|scope| scope.to_string())
+            .collect()
+    }
+}
@@ -0,0 +1,45 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::login::encrypt::Encrypt;
+use crate::login::keyring::Keyring;
+
+pub const HEADER: &str = "X-CSRF-Token";
+pub const FIELD: &str = "csrf_token";
+pub const PLACEHOLDER: &str = "{{csrf_token}}";
+pub const KEY_NAME: &str = "csrf";
+
+pub struct Csrf; // Tokens are derived from the session so nothing extra needs storing
+
+impl Csrf {
+    pub fn token(keyring: &Keyring, session_token: &str) -> String {
+        let mut mac = match keyring.key(KEY_NAME).map(|key| Hmac::<Sha256>::new_from_slice(&*key)) {
+            Some(Ok(mac)) => mac,
+            _ => return String::new()
+        };
+        mac.update(b"csrf:");
+        mac.update(session_token.as_bytes());
+        Encrypt::hex(&mac.finalize().into_bytes())
+    }
+
+    pub fn verify(keyring: &Keyring, session_token: &str, presented: &str) -> bool {
+        let expected = Self::token(keyring, session_token);
+        !expected.is_empty() && Encrypt::constant_eq(expected.as_bytes(), presented.trim().as_bytes())
+    }
+
+    pub fn from_form(body: &str) -> Option<String> { // application/x-www-form-urlencoded bodies
+        body.split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(name, _)| *name == FIELD)
+            .map(|(_, value)| value.to_string())
+    }
+
+    pub fn same_origin(source: &str, host: &str) -> bool { // source is an Origin or Referer header
+        let without_scheme = match source.split_once("://") {
+            Some((_, rest)) => rest,
+            None => return false
+        };
+        let source_host = without_scheme.split('/').next().unwrap_or_default();
+        source_host.eq_ignore_ascii_case(host.trim())
+    }
+}
@@ -1,26 +1,146 @@
-use aes_gcm::{Aes256Gcm, aead::{Aead, KeyInit, OsRng, Nonce}};
+use aes_gcm::{Aes256Gcm, aead::{Aead, KeyInit, OsRng, Payload}};
 use aes_gcm::aead::generic_array::GenericArray;
-use aes_gcm::Key;
+use argon2::{Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version};
+use argon2::password_hash::SaltString;
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
 use rand::{RngCore, Rng};
-use std::str;
+use std::io::Write;
+use std::path::Path;
 
 use sha2::{Sha256, Digest};
 
+use crate::error::Error;
+
+pub const ENVELOPE_VERSION: u8 = 1;
+pub const PLAINTEXT_MARK: &str = "plain:"; // Prefixed by migration 1 to values the baseline stored as-is
+const NONCE_LENGTH: usize = 12;
+const TAG_LENGTH: usize = 16;
+
 pub struct Encrypt;
 pub struct Decrypt;
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum CipherError {
+    Encoding, // Not base64url
+    Truncated, // Shorter than its own header says it should be
+    UnsupportedVersion(u8),
+    InvalidKeyId,
+    UnknownKey(String), // No key with this ID to decrypt with
+    Authentication, // Wrong key, wrong associated data or tampered ciphertext
+    Utf8,
+    Failed
+}
+
+impl std::fmt::Display for CipherError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CipherError::Encoding => write!(f, "ciphertext is not valid base64url"),
+            CipherError::Truncated => write!(f, "ciphertext is truncated"),
+            CipherError::UnsupportedVersion(version) => write!(f, "unsupported ciphertext version {}", version),
+            CipherError::InvalidKeyId => write!(f, "key ID must be 1 to 255 bytes"),
+            CipherError::UnknownKey(id) => write!(f, "no key with ID {}", id),
+            CipherError::Authentication => write!(f, "ciphertext failed authentication"),
+            CipherError::Utf8 => write!(f, "plaintext is not valid UTF-8"),
+            CipherError::Failed => write!(f, "encryption failed")
+        }
+    }
+}
+
+impl std::error::Error for CipherError {}
+
+struct Envelope { // version (1) | key ID length (1) | key ID | nonce (12) | ciphertext + tag
+    header: Vec<u8>, // version, key ID length and key ID, authenticated along with the caller's associated data
+    key_id: String,
+    nonce: [u8; NONCE_LENGTH],
+    ciphertext: Vec<u8>
+}
+
+impl Envelope {
+    fn parse(encoded: &str) -> Result<Self, CipherError> {
+        let bytes = URL_SAFE_NO_PAD.decode(encoded.trim()).map_err(|_| CipherError::Encoding)?;
+        let (version, length) = match bytes.as_slice() {
+            [version, length, ..] => (*version, *length as usize),
+            _ => return Err(CipherError::Truncated)
+        };
+        if version != ENVELOPE_VERSION {
+            return Err(CipherError::UnsupportedVersion(version));
+        }
+        let header_length = 2 + length;
+        if length == 0 || bytes.len() < header_length + NONCE_LENGTH + TAG_LENGTH {
+            return Err(CipherError::Truncated);
+        }
+
+        let key_id = std::str::from_utf8(&bytes[2..header_length]).map_err(|_| CipherError::InvalidKeyId)?;
+        let nonce = bytes[header_length..header_length + NONCE_LENGTH].try_into().map_err(|_| CipherError::Truncated)?;
+        Ok(Self {
+            header: bytes[..header_length].to_vec(),
+            key_id: key_id.to_string(),
+            nonce,
+            ciphertext: bytes[header_length + NONCE_LENGTH..].to_vec()
+        })
+    }
+
+    fn header(key_id: &str) -> Result<Vec<u8>, CipherError> {
+        if key_id.is_empty() || key_id.len() > u8::MAX as usize {
+            return Err(CipherError::InvalidKeyId);
+        }
+        let mut header = vec![ENVELOPE_VERSION, key_id.len() as u8];
+        header.extend_from_slice(key_id.as_bytes());
+        Ok(header)
+    }
+
+    fn associated_data(header: &[u8], aad: &[u8]) -> Vec<u8> {
+        let mut data = header.to_vec();
+        data.extend_from_slice(aad);
+        data
+    }
+}
+
 pub struct Keys;
 
 impl Keys {
+    #[allow(clippy::new_ret_no_self)]
     pub fn new() -> [u8; 32] {
         let mut rng = rand::thread_rng(); 
         let key: [u8; 32] = rng.gen();     
         key            
     }
+
+    pub fn load(path: &Path) -> Result<[u8; 32], Error> { // Reads a base64 key file, creating it on first run
+        match std::fs::read_to_string(path) {
+            Ok(data) => STANDARD.decode(data.trim())
+                .ok()
+                .and_then(|key| key.try_into().ok())
+                .ok_or_else(|| Error::Config(format!("{} is not a base64 encoded 32 byte key", path.display()))),
+            Err(_) => {
+                let key = Self::new();
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                let mut options = std::fs::OpenOptions::new();
+                options.write(true).create_new(true);
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::OpenOptionsExt;
+                    options.mode(0o600);
+                }
+                let mut file = options.open(path)?;
+                file.write_all(STANDARD.encode(key).as_bytes())?;
+                Ok(key)
+            }
+        }
+    }
+
+    pub fn token() -> String { // 256 random bits, hex encoded
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        Encrypt::hex(&bytes)
+    }
 }
 
 impl Encrypt {
-    pub fn sha256(plaintext: &String) -> String{
+    pub fn sha256(plaintext: &str) -> String {
         let mut hasher = Sha256::new();
         hasher.update(plaintext);
     
@@ -28,35 +148,143 @@ impl Encrypt {
         format!("{:x}", result)
     }
 
-    pub fn aes(key: &[u8; 32], plaintext: &String) -> Option<(String, [u8; 12])> {
+    pub fn password(plaintext: &str) -> Result<String, CipherError> { // Stored as $argon2id$v=19$m=..,t=..,p=..$salt$hash
+        let salt = SaltString::generate(&mut OsRng);
+        Self::argon2()
+            .hash_password(plaintext.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|_| CipherError::Failed)
+    }
+
+    pub fn verify_password(plaintext: &str, stored: &str) -> bool { // Also accepts marked baseline plaintext
+        if let Some(legacy) = stored.strip_prefix(PLAINTEXT_MARK) {
+            return Self::constant_eq(Self::sha256(plaintext).as_bytes(), Self::sha256(legacy).as_bytes()); // Hashed first so the length isn't leaked
+        }
+        PasswordHash::new(stored).is_ok_and(|hash| Self::argon2().verify_password(plaintext.as_bytes(), &hash).is_ok())
+    }
+
+    pub fn password_outdated(stored: &str) -> bool { // Hashed with anything but the current algorithm and costs, rehash it on the next sign in
+        let current = Params::DEFAULT;
+        match PasswordHash::new(stored) {
+            Ok(hash) => hash.algorithm != Algorithm::Argon2id.ident()
+                || hash.version != Some(Version::V0x13.into())
+                || Params::try_from(&hash).map_or(true, |params| {
+                    (params.m_cost(), params.t_cost(), params.p_cost()) != (current.m_cost(), current.t_cost(), current.p_cost())
+                }),
+            Err(_) => true
+        }
+    }
+
+    fn argon2() -> Argon2<'static> {
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, Params::DEFAULT)
+    }
+
+    pub fn constant_eq(a: &[u8], b: &[u8]) -> bool {
+        if a.len() != b.len() {
+            return false;
+        }
+        a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+    }
+
+    pub fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    pub fn aes(key: &[u8; 32], key_id: &str, plaintext: &str, aad: &[u8]) -> Result<String, CipherError> { // -> base64url envelope, aad binds it to e.g. a record ID
+        let header = Envelope::header(key_id)?;
         let cipher = Aes256Gcm::new(GenericArray::from_slice(key));
-        let mut nonce = [0u8; 12];
+        let mut nonce = [0u8; NONCE_LENGTH];
         OsRng.fill_bytes(&mut nonce);
 
-        match cipher.encrypt(GenericArray::from_slice(&nonce), plaintext.as_bytes()) {
-            Ok(ciphertext) => Some((base64::encode(&ciphertext), nonce)),
-            Err(_) => None
-        }
+        let payload = Payload {
+            msg: plaintext.as_bytes(),
+            aad: &Envelope::associated_data(&header, aad)
+        };
+        let ciphertext = cipher
+            .encrypt(GenericArray::from_slice(&nonce), payload)
+            .map_err(|_| CipherError::Failed)?;
+
+        let mut envelope = header;
+        envelope.extend_from_slice(&nonce);
+        envelope.extend_from_slice(&ciphertext);
+        Ok(URL_SAFE_NO_PAD.encode(envelope))
     }
 }
 
 impl Decrypt {
-    pub fn aes(key: &[u8; 32], ciphertext: &String, nonce: &[u8; 12]) -> Option<String> {
+    pub fn aes(key: &[u8; 32], envelope: &str, aad: &[u8]) -> Result<String, CipherError> {
+        let envelope = Envelope::parse(envelope)?;
         let cipher = Aes256Gcm::new(GenericArray::from_slice(key));
 
-        let ciphertext = match base64::decode(ciphertext) {
-            Ok(data) => data,
-            Err(_) => return None
+        let payload = Payload {
+            msg: &envelope.ciphertext,
+            aad: &Envelope::associated_data(&envelope.header, aad)
         };
+        let plaintext = cipher
+            .decrypt(GenericArray::from_slice(&envelope.nonce), payload)
+            .map_err(|_| CipherError::Authentication)?;
+        String::from_utf8(plaintext).map_err(|_| CipherError::Utf8)
+    }
 
-        match cipher.decrypt(GenericArray::from_slice(nonce), ciphertext.as_ref()) {
-            Ok(plaintext) => {
-                match String::from_utf8(plaintext) {
-                    Ok(string) => Some(string),
-                    Err(_) => None,
-                }
-            }
-            Err(_) => None
-        }
+    pub fn key_id(envelope: &str) -> Result<String, CipherError> { // Which key to decrypt with, read without decrypting
+        Envelope::parse(envelope).map(|envelope| envelope.key_id)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [7; 32];
+
+    fn tampered(envelope: &str, index: usize) -> String { // index from the end, so it lands in the ciphertext or tag
+        let mut bytes = URL_SAFE_NO_PAD.decode(envelope).unwrap();
+        let position = bytes.len() - 1 - index;
+        bytes[position] ^= 1;
+        URL_SAFE_NO_PAD.encode(bytes)
+    }
+
+    #[test]
+    fn envelope_round_trip() {
+        let envelope = Encrypt::aes(&KEY, "fields-1", "secret", b"users.email").unwrap();
+        assert_eq!(Decrypt::key_id(&envelope).unwrap(), "fields-1");
+        assert_eq!(Decrypt::aes(&KEY, &envelope, b"users.email").unwrap(), "secret");
+        assert_ne!(Encrypt::aes(&KEY, "fields-1", "secret", b"users.email").unwrap(), envelope); // Fresh nonce each time
+    }
+
+    #[test]
+    fn envelope_rejects_tampering() {
+        let envelope = Encrypt::aes(&KEY, "fields-1", "secret", b"users.email").unwrap();
+        assert_eq!(Decrypt::aes(&KEY, &tampered(&envelope, 0), b"users.email"), Err(CipherError::Authentication));
+        assert_eq!(Decrypt::aes(&KEY, &tampered(&envelope, 20), b"users.email"), Err(CipherError::Authentication));
+        assert_eq!(Decrypt::aes(&KEY, &envelope, b"users.name"), Err(CipherError::Authentication));
+        assert_eq!(Decrypt::aes(&[8; 32], &envelope, b"users.email"), Err(CipherError::Authentication));
+    }
+
+    #[test]
+    fn envelope_rejects_malformed() {
+        let envelope = Encrypt::aes(&KEY, "fields-1", "secret", b"").unwrap();
+        assert_eq!(Decrypt::aes(&KEY, "not base64!", b""), Err(CipherError::Encoding));
+        assert_eq!(Decrypt::aes(&KEY, &envelope[..20], b""), Err(CipherError::Truncated));
+
+        let mut bytes = URL_SAFE_NO_PAD.decode(&envelope).unwrap();
+        bytes[0] = 2;
+        assert_eq!(Decrypt::aes(&KEY, &URL_SAFE_NO_PAD.encode(bytes), b""), Err(CipherError::UnsupportedVersion(2)));
+        assert_eq!(Encrypt::aes(&KEY, "", "secret", b""), Err(CipherError::InvalidKeyId));
+    }
+
+    #[test]
+    fn passwords() {
+        let stored = Encrypt::password("Passw0rd1").unwrap();
+        assert!(stored.starts_with("$argon2id$"));
+        assert!(Encrypt::verify_password("Passw0rd1", &stored));
+        assert!(!Encrypt::verify_password("Passw0rd2", &stored));
+        assert!(!Encrypt::password_outdated(&stored));
+        assert!(!Encrypt::verify_password("Passw0rd1", &format!("salt${}", Encrypt::sha256("saltPassw0rd1"))));
+
+        let baseline = format!("{}Passw0rd1", PLAINTEXT_MARK);
+        assert!(Encrypt::verify_password("Passw0rd1", &baseline));
+        assert!(!Encrypt::verify_password("Passw0rd", &baseline));
+        assert!(Encrypt::password_outdated(&baseline));
+    }
+}
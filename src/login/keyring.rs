@@ -0,0 +1,236 @@
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroize;
+
+use crate::error::Error;
+use crate::login::encrypt::{CipherError, Encrypt, Decrypt, Keys};
+use crate::tools::utils::now;
+
+pub const MASTER_KEY_ENV: &str = "SIMPLE_TCP_SERVER_MASTER_KEY";
+const MASTER_KEY_ID: &str = "master";
+
+#[derive(Clone)]
+pub struct Key([u8; 32]); // Wiped from memory when dropped
+
+impl Key {
+    pub fn new(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn generate() -> Self {
+        Self(Keys::new())
+    }
+
+    pub fn from_base64(encoded: &str) -> Option<Self> {
+        let mut bytes = STANDARD.decode(encoded.trim()).ok()?;
+        let key = bytes.as_slice().try_into().ok().map(Self);
+        bytes.zeroize();
+        key
+    }
+}
+
+impl Deref for Key {
+    type Target = [u8; 32];
+
+    fn deref(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl Drop for Key {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+#[derive(Clone)]
+struct DataKey {
+    id: String,
+    name: String,
+    key: Key,
+    created: u64,
+    active: bool
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredKey { // A data key as written to disk, wrapped by the master key
+    id: String,
+    name: String,
+    wrapped: String, // Envelope bound to the key's ID
+    created: u64,
+    active: bool
+}
+
+pub struct Decrypted {
+    pub plaintext: String,
+    pub rotated: Option<String> // Re-encrypted under the active key when the ciphertext used a retired one
+}
+
+pub struct Keyring { // Named data keys, stored wrapped by a master key from the environment or a key file
+    path: PathBuf,
+    master: Key,
+    keys: RwLock<Vec<DataKey>> // Shared with the databases, so rotation only needs &self
+}
+
+impl Keyring {
+    pub fn open(key_file: &Path, keyring_file: &Path) -> Result<Self, Error> {
+        let master = match std::env::var(MASTER_KEY_ENV) {
+            Ok(encoded) => Key::from_base64(&encoded).ok_or_else(|| Error::Config(format!("{} is not a base64 encoded 32 byte key", MASTER_KEY_ENV)))?,
+            Err(_) => Key::new(Keys::load(key_file)?)
+        };
+        Self::load(keyring_file, master)
+    }
+
+    pub fn load(path: &Path, master: Key) -> Result<Self, Error> {
+        let mut keys = Vec::new();
+        let data = match std::fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(_) => String::from("[]") // First run, nothing stored yet
+        };
+
+        let stored: Vec<StoredKey> = serde_json::from_str(&data).map_err(|e| Error::Config(format!("{}: {}", path.display(), e)))?;
+        for entry in stored {
+            let mut encoded = Decrypt::aes(&master, &entry.wrapped, entry.id.as_bytes()) // Wrong master key fails here
+                .map_err(|e| Error::Config(format!("could not unwrap data key {}: {}", entry.id, e)))?;
+            let key = Key::from_base64(&encoded);
+            encoded.zeroize();
+            keys.push(DataKey {
+                key: key.ok_or_else(|| Error::Config(format!("data key {} is not 32 bytes", entry.id)))?,
+                id: entry.id,
+                name: entry.name,
+                created: entry.created,
+                active: entry.active
+            });
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            master,
+            keys: RwLock::new(keys)
+        })
+    }
+
+    pub fn ensure(&self, name: &str) -> Result<(), Error> { // Creates the first key for name if there is none
+        if self.key(name).is_none() {
+            self.rotate(name)?;
+        }
+        Ok(())
+    }
+
+    pub fn rotate(&self, name: &str) -> Result<String, Error> { // New data written with the new key, old keys stay for reading
+        let id = format!("{}-{}", name, &Keys::token()[..8]);
+        let mut keys = self.keys.write().map_err(|_| CipherError::Failed)?;
+        let mut rotated = keys.clone(); // Only used once it is on disk, so nothing is encrypted under a key a restart would lose
+        for key in rotated.iter_mut().filter(|key| key.name == name) {
+            key.active = false;
+        }
+        rotated.push(DataKey {
+            id: id.clone(),
+            name: name.to_string(),
+            key: Key::generate(),
+            created: now(),
+            active: true
+        });
+        self.save(&rotated)?;
+        *keys = rotated;
+        Ok(id)
+    }
+
+    pub fn key(&self, name: &str) -> Option<Key> {
+        let keys = self.keys.read().ok()?;
+        Self::active(&keys, name).map(|key| key.key.clone())
+    }
+
+    pub fn encrypt(&self, name: &str, plaintext: &str, aad: &[u8]) -> Result<String, CipherError> {
+        let keys = self.keys.read().map_err(|_| CipherError::Failed)?;
+        let key = Self::active(&keys, name).ok_or_else(|| CipherError::UnknownKey(name.to_string()))?;
+        Encrypt::aes(&key.key, &key.id, plaintext, aad)
+    }
+
+    pub fn decrypt(&self, ciphertext: &str, aad: &[u8]) -> Result<Decrypted, CipherError> { // aad must match what it was encrypted with
+        let id = Decrypt::key_id(ciphertext)?;
+        let keys = self.keys.read().map_err(|_| CipherError::Failed)?;
+        let key = keys
+            .iter()
+            .find(|key| key.id == id)
+            .ok_or(CipherError::UnknownKey(id))?;
+        let plaintext = Decrypt::aes(&key.key, ciphertext, aad)?;
+
+        let rotated = match Self::active(&keys, &key.name) {
+            Some(active) if !key.active => Encrypt::aes(&active.key, &active.id, &plaintext, aad).ok(),
+            _ => None
+        };
+        Ok(Decrypted { plaintext, rotated })
+    }
+
+    fn active<'a>(keys: &'a [DataKey], name: &str) -> Option<&'a DataKey> {
+        keys.iter().find(|key| key.name == name && key.active)
+    }
+
+    fn save(&self, keys: &[DataKey]) -> Result<(), Error> { // Written to a temporary file then renamed so a crash can't truncate the keyring
+        let mut stored = Vec::with_capacity(keys.len());
+        for key in keys {
+            let mut encoded = STANDARD.encode(*key.key);
+            let wrapped = Encrypt::aes(&self.master, MASTER_KEY_ID, &encoded, key.id.as_bytes());
+            encoded.zeroize();
+            stored.push(StoredKey {
+                id: key.id.clone(),
+                name: key.name.clone(),
+                wrapped: wrapped?,
+                created: key.created,
+                active: key.active
+            });
+        }
+
+        let data = serde_json::to_string_pretty(&stored).map_err(|e| Error::Config(e.to_string()))?;
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let temporary = self.path.with_extension("tmp");
+        std::fs::write(&temporary, data)?;
+        std::fs::rename(&temporary, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotation_survives_reload() {
+        let path = std::env::temp_dir().join(format!("keyring-{}.json", &Keys::token()[..12]));
+        let master = Key::generate();
+        let keyring = Keyring::load(&path, master.clone()).unwrap();
+        keyring.ensure("fields").unwrap();
+        let old = keyring.encrypt("fields", "secret", b"aad").unwrap();
+        keyring.rotate("fields").unwrap();
+
+        let reloaded = Keyring::load(&path, master).unwrap();
+        let decrypted = reloaded.decrypt(&old, b"aad").unwrap();
+        assert_eq!(decrypted.plaintext, "secret");
+        assert_eq!(reloaded.decrypt(&decrypted.rotated.unwrap(), b"aad").unwrap().rotated, None);
+        assert!(Keyring::load(&path, Key::generate()).is_err());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn failed_rotation_keeps_the_active_key() {
+        let path = std::env::temp_dir().join(format!("keyring-{}.json", &Keys::token()[..12]));
+        let mut keyring = Keyring::load(&path, Key::generate()).unwrap();
+        keyring.ensure("fields").unwrap();
+        let before = keyring.encrypt("fields", "secret", b"").unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        keyring.path = path.join("keyring.json"); // A file stands where its directory should be, so saving fails
+        std::fs::write(&path, "").unwrap();
+        assert!(keyring.rotate("fields").is_err());
+        let after = keyring.encrypt("fields", "secret", b"").unwrap();
+        assert_eq!(Decrypt::key_id(&after).unwrap(), Decrypt::key_id(&before).unwrap());
+        let _ = std::fs::remove_file(&path);
+    }
+}
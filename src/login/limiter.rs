@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+
+use crate::tools::utils::now;
+
+const MAX_TRACKED: usize = 4096;
+
+pub struct RateLimiter {
+    max_attempts: u64,
+    lockout_seconds: u64,
+    failures: HashMap<String, Failures>
+}
+
+struct Failures {
+    count: u64,
+    last: u64,
+    blocked_until: u64
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Lockout {
+    Account(String), // username
+    Address(String) // ip
+}
+
+impl RateLimiter {
+    pub fn new(max_attempts: u64, lockout_seconds: u64) -> Self {
+        Self {
+            max_attempts: max_attempts.max(1),
+            lockout_seconds,
+            failures: HashMap::new()
+        }
+    }
+
+    pub fn retry_after(&self, ip: &str, username: &str) -> Option<u64> { // Seconds until either key may try again
+        let current = now();
+        [Self::ip_key(ip), Self::user_key(username)]
+            .iter()
+            .filter_map(|key| self.failures.get(key))
+            .map(|failures| failures.blocked_until.saturating_sub(current))
+            .filter(|wait| *wait > 0)
+            .max()
+    }
+
+    pub fn fail(&mut self, ip: &str, username: &str) -> Vec<Lockout> {
+        let current = now();
+        if self.failures.len() >= MAX_TRACKED {
+            let lockout_seconds = self.lockout_seconds;
+            self.failures.retain(|_, failures| current.saturating_sub(failures.last) <= lockout_seconds);
+        }
+
+        let mut lockouts = Vec::new();
+        if self.record(Self::ip_key(ip), current) {
+            lockouts.push(Lockout::Address(ip.to_string()));
+        }
+        if self.record(Self::user_key(username), current) {
+            lockouts.push(Lockout::Account(username.to_string()));
+        }
+        lockouts
+    }
+
+    pub fn clear(&mut self, username: &str) { // After a successful login or an admin unlock
+        self.failures.remove(&Self::user_key(username));
+    }
+
+    fn record(&mut self, key: String, current: u64) -> bool { // Returns true when this failure triggers a lockout
+        let failures = self.failures.entry(key).or_insert(Failures { count: 0, last: current, blocked_until: 0 });
+        if current.saturating_sub(failures.last) > self.lockout_seconds {
+            failures.count = 0;
+        }
+        failures.count += 1;
+        failures.last = current;
+
+        if failures.count >= self.max_attempts {
+            failures.blocked_until = current + self.lockout_seconds;
+            true
+        } else {
+            let backoff = 1u64 << (failures.count - 1).min(32); // 1s, 2s, 4s, ...
+            failures.blocked_until = current + backoff.min(self.lockout_seconds);
+            false
+        }
+    }
+
+    fn ip_key(ip: &str) -> String {
+        format!("ip:{}", ip)
+    }
+
+    fn user_key(username: &str) -> String {
+        format!("user:{}", username)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backs_off_then_locks_out() {
+        let mut limiter = RateLimiter::new(4, 900);
+        assert_eq!(limiter.retry_after("10.0.0.1", "bob"), None);
+
+        assert!(limiter.fail("10.0.0.1", "bob").is_empty());
+        assert!(limiter.retry_after("10.0.0.1", "bob").is_none_or(|wait| wait <= 1)); // May already have passed
+        assert!(limiter.fail("10.0.0.1", "bob").is_empty());
+        assert!(matches!(limiter.retry_after("10.0.0.1", "bob"), Some(1..=2)));
+        assert!(limiter.fail("10.0.0.1", "bob").is_empty());
+        assert!(matches!(limiter.retry_after("10.0.0.1", "bob"), Some(3..=4)));
+
+        let lockouts = limiter.fail("10.0.0.1", "bob");
+        assert_eq!(lockouts, vec![Lockout::Address(String::from("10.0.0.1")), Lockout::Account(String::from("bob"))]);
+        assert!(matches!(limiter.retry_after("10.0.0.2", "bob"), Some(899..=900)));
+        assert!(matches!(limiter.retry_after("10.0.0.1", "alice"), Some(899..=900)));
+        assert_eq!(limiter.retry_after("10.0.0.2", "alice"), None);
+    }
+
+    #[test]
+    fn clear_resets_the_account_only() {
+        let mut limiter = RateLimiter::new(2, 900);
+        limiter.fail("10.0.0.1", "bob");
+        limiter.fail("10.0.0.1", "bob");
+        limiter.clear("bob");
+        assert_eq!(limiter.retry_after("10.0.0.2", "bob"), None);
+        assert!(limiter.retry_after("10.0.0.1", "alice").is_some()); // The address stays blocked
+    }
+}
@@ -1,11 +1,52 @@
-//use crate::login::encryptor::Encryptor;
-//use crate::database::db::Database;
+use crate::database::db::{Database, AQuery, GQuery, UserOrder};
+use crate::error::Error;
+use crate::from_row;
+use crate::login::encrypt::Encrypt;
+use crate::login::register::{Register, RegisterStatus};
+use crate::login::access::{Access, DEFAULT_ROLE};
+use crate::login::keyring::Keyring;
+use crate::login::totp::Totp;
+use crate::tools::utils::now;
 
 pub struct Login {
     pub username: String,
     pub password: String
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub struct User {
+    pub username: String,
+    pub name: Option<String>,
+    pub email: Option<String>,
+    pub site: Option<String>
+}
+
+from_row!(User { username, name, email, site });
+
+#[derive(Debug, PartialEq)]
+pub enum LoginResult {
+    Authenticated(User),
+    TwoFactorRequired(User), // Password was correct, a TOTP or recovery code must follow
+    BadCredentials,
+    Locked,
+    Disabled
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ProfileStatus {
+    Updated,
+    NotFound,
+    Invalid(&'static str),
+    Conflict(&'static str)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PasswordStatus {
+    Changed,
+    Rejected(LoginResult), // Current credentials did not authenticate
+    Invalid(&'static str)
+}
+
 impl Login {
     pub fn new(username: String, password: String) -> Self{
         Self {
@@ -14,11 +55,194 @@ impl Login {
         }
     }
 
-    pub fn attempt(/* db: Database,*/) {
-        
+    pub fn attempt(&self, database: &Database) -> Result<LoginResult, Error> {
+        let (locked, disabled, locked_until) = match Self::status(database, &self.username)? {
+            Some(status) => status,
+            None => return Ok(LoginResult::BadCredentials)
+        };
+        if locked || locked_until > now() {
+            return Ok(LoginResult::Locked);
+        }
+
+        let stored = database.get::<String>(&GQuery::Password { username: self.username.clone() })?
+            .into_iter()
+            .filter_map(|row| row.into_iter().next())
+            .find(|stored| Encrypt::verify_password(&self.password, stored));
+        let stored = match stored {
+            Some(stored) => stored,
+            None => return Ok(LoginResult::BadCredentials)
+        };
+        if disabled {
+            return Ok(LoginResult::Disabled);
+        }
+        if Encrypt::password_outdated(&stored) {
+            self.rehash(database);
+        }
+
+        Ok(match Self::user(database, &self.username)? {
+            Some(user) if Totp::enabled(database, &user.username)? => LoginResult::TwoFactorRequired(user),
+            Some(user) => LoginResult::Authenticated(user),
+            None => LoginResult::BadCredentials
+        })
+    }
+
+    pub fn create(&self, database: &Database, email: Option<String>, name: Option<String>, site: Option<String>) -> Result<RegisterStatus, Error> {
+        if Self::exists(database, &self.username)? {
+            return Ok(RegisterStatus::Conflict("Username already taken"));
+        }
+        if let Some(email) = &email {
+            if !database.get::<String>(&GQuery::Email { email: email.clone() })?.is_empty() {
+                return Ok(RegisterStatus::Conflict("Email already registered"));
+            }
+        }
+
+        let query = AQuery::User {
+            username: self.username.clone(),
+            password: Encrypt::password(&self.password)?,
+            name,
+            email,
+            site
+        };
+        database.transaction(|database| {
+            database.add(&query)?;
+            Access::grant(database, &self.username, DEFAULT_ROLE)
+        })?;
+        Ok(RegisterStatus::Created)
+    }
+
+    pub fn change_password(&self, database: &Database, keyring: &Keyring, new_password: &str, code: Option<&str>) -> Result<PasswordStatus, Error> { // Ends every session, token and API key of the user
+        match self.attempt(database)? {
+            LoginResult::Authenticated(_) => {},
+            LoginResult::TwoFactorRequired(user) => match code { // The password alone isn't enough, same as signing in
+                Some(code) if Totp::verify(database, keyring, &user.username, code)? => {},
+                _ => return Ok(PasswordStatus::Rejected(LoginResult::TwoFactorRequired(user)))
+            },
+            result => return Ok(PasswordStatus::Rejected(result))
+        }
+        if let Err(reason) = Register::validate_password(&self.username, new_password) {
+            return Ok(PasswordStatus::Invalid(reason));
+        }
+
+        database.add_batch(&[
+            AQuery::Password { username: self.username.clone(), password: Encrypt::password(new_password)? },
+            AQuery::EndSessions { username: self.username.clone() },
+            AQuery::RevokeTokens { username: self.username.clone(), before: now() as i64 }, // A stolen token doesn't outlive the change
+            AQuery::RevokeApiKeys { username: self.username.clone() }
+        ])?;
+        Ok(PasswordStatus::Changed)
+    }
+
+    fn rehash(&self, database: &Database) { // Best effort, the old hash still verifies until this succeeds
+        let rehashed = Encrypt::password(&self.password)
+            .map_err(Error::from)
+            .and_then(|password| database.add(&AQuery::Password { username: self.username.clone(), password }));
+        if let Err(e) = rehashed {
+            println!("Failed to rehash the password of {}: {}", self.username, e);
+        }
+    }
+
+    pub fn user(database: &Database, username: &str) -> Result<Option<User>, Error> {
+        database.first::<User>(&GQuery::User { username: username.to_string() })
+    }
+
+    pub fn exists(database: &Database, username: &str) -> Result<bool, Error> {
+        let exists = database.first::<(bool,)>(&GQuery::UserExists { username: username.to_string() })?;
+        Ok(exists.is_some_and(|(exists,)| exists))
+    }
+
+    pub fn count(database: &Database) -> Result<u64, Error> {
+        let count = database.first::<(i64,)>(&GQuery::UserCount)?;
+        Ok(count.map_or(0, |(count,)| count.max(0) as u64))
     }
 
-    pub fn create() {
+    pub fn list(database: &Database, order: UserOrder, descending: bool, limit: u64, offset: u64) -> Result<Vec<User>, Error> { // One page of users
+        let query = GQuery::Users {
+            order,
+            descending,
+            limit: limit.min(i64::MAX as u64) as i64,
+            offset: offset.min(i64::MAX as u64) as i64
+        };
+        database.fetch::<User>(&query)
+    }
+
+    pub fn update_profile(database: &Database, username: &str, name: Option<String>, email: Option<String>, site: Option<String>) -> Result<ProfileStatus, Error> { // Unset fields are kept
+        if !Self::exists(database, username)? {
+            return Ok(ProfileStatus::NotFound);
+        }
+        if let Some(email) = &email {
+            if let Err(reason) = Register::validate_email(email) {
+                return Ok(ProfileStatus::Invalid(reason));
+            }
+            if database.get::<String>(&GQuery::Email { email: email.clone() })?.iter().any(|row| row[0] != username) {
+                return Ok(ProfileStatus::Conflict("Email already registered"));
+            }
+        }
 
+        let query = AQuery::Profile {
+            username: username.to_string(),
+            name,
+            email,
+            site
+        };
+        database.add(&query)?;
+        Ok(ProfileStatus::Updated)
     }
-} 
\ No newline at end of file
+
+    pub fn delete(database: &Database, username: &str) -> Result<(), Error> { // Removes the user along with their roles, sessions, keys and pending codes
+        let username = username.to_string();
+        let queries = [
+            AQuery::EndSessions { username: username.clone() },
+            AQuery::RevokeRoles { username: username.clone() },
+            AQuery::DeleteApiKeys { username: username.clone() },
+            AQuery::ClearRecoveryCodes { username: username.clone() },
+            AQuery::EndResets { username: username.clone() },
+            AQuery::DeleteUser { username }
+        ];
+        database.add_batch(&queries)
+    }
+
+    pub fn lock(database: &Database, username: &str, seconds: u64) -> Result<(), Error> {
+        database.add(&AQuery::Lock {
+            username: username.to_string(),
+            until: (now() + seconds) as i64
+        })
+    }
+
+    pub fn unlock(database: &Database, username: &str) -> Result<(), Error> {
+        database.add(&AQuery::Unlock { username: username.to_string() })
+    }
+
+    pub fn retry_after(database: &Database, username: &str) -> Result<Option<u64>, Error> { // Remaining seconds of a temporary lockout
+        Ok(Self::status(database, username)?
+            .map(|(_, _, locked_until)| locked_until.saturating_sub(now()))
+            .filter(|remaining| *remaining > 0))
+    }
+
+    fn status(database: &Database, username: &str) -> Result<Option<(bool, bool, u64)>, Error> { // -> (locked, disabled, locked_until)
+        let status = database.first::<(bool, bool, i64)>(&GQuery::Status { username: username.to_string() })?;
+        Ok(status.map(|(locked, disabled, locked_until)| (locked, disabled, locked_until.max(0) as u64)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::login::apikey::ApiKeys;
+    use crate::login::testing::Fixture;
+    use crate::login::token::Tokens;
+
+    #[test]
+    fn change_password_revokes_credentials() {
+        let f = Fixture::open();
+        f.user("bob", "Passw0rd1");
+        let token = Tokens::issue(&f.database, &f.keyring, "bob", "test", 60).unwrap();
+        let (_, key) = ApiKeys::create(&f.database, "bob", "ci", &[String::from("logs/")]).unwrap();
+
+        let login = Login::new(String::from("bob"), String::from("Passw0rd1"));
+        assert_eq!(login.change_password(&f.database, &f.keyring, "Passw0rd2", None).unwrap(), PasswordStatus::Changed);
+        assert_eq!(Tokens::verify(&f.database, &f.keyring, &token, "test", 60).unwrap(), None);
+        assert_eq!(ApiKeys::authenticate(&f.database, &key, "logs/summary").unwrap(), None);
+        assert!(matches!(login.attempt(&f.database).unwrap(), LoginResult::BadCredentials));
+        assert!(matches!(Login::new(String::from("bob"), String::from("Passw0rd2")).attempt(&f.database).unwrap(), LoginResult::Authenticated(_)));
+    }
+}
@@ -1,2 +1,15 @@
+#[allow(clippy::module_inception)]
 pub mod login;
-pub mod encrypt;
\ No newline at end of file
+pub mod encrypt;
+pub mod register;
+pub mod limiter;
+pub mod session;
+pub mod access;
+pub mod token;
+pub mod totp;
+pub mod reset;
+pub mod apikey;
+pub mod csrf;
+pub mod keyring;
+#[cfg(test)]
+pub mod testing;
@@ -0,0 +1,86 @@
+use serde_json::Value;
+
+use crate::database::db::Database;
+use crate::error::Error;
+use crate::login::login::Login;
+
+pub struct Register {
+    pub username: String,
+    pub password: String,
+    pub email: String,
+    pub name: Option<String>,
+    pub site: Option<String>
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RegisterStatus {
+    Created,
+    Invalid(&'static str), // 400
+    Conflict(&'static str) // 409
+}
+
+impl Register {
+    pub fn from_json(json: &Value) -> Option<Self> {
+        let field = |key: &str| json.get(key).and_then(|value| value.as_str()).map(|s| s.trim().to_string());
+        Some(Self {
+            username: field("username")?,
+            password: json.get("password")?.as_str()?.to_string(),
+            email: field("email")?,
+            name: field("name").filter(|s| !s.is_empty()),
+            site: field("site").filter(|s| !s.is_empty())
+        })
+    }
+
+    pub fn validate(&self) -> Result<(), &'static str> {
+        Self::validate_username(&self.username)?;
+        Self::validate_email(&self.email)?;
+        Self::validate_password(&self.username, &self.password)
+    }
+
+    pub fn validate_username(username: &str) -> Result<(), &'static str> {
+        if username.len() < 3 || username.len() > 32 {
+            return Err("Username must be between 3 and 32 characters");
+        }
+        if !username.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.') {
+            return Err("Username may only contain letters, digits, '_', '-' and '.'");
+        }
+        Ok(())
+    }
+
+    pub fn validate_email(email: &str) -> Result<(), &'static str> {
+        let (local, domain) = match email.split_once('@') {
+            Some(parts) => parts,
+            None => return Err("Invalid email address")
+        };
+        let domain_valid = domain.contains('.')
+            && !domain.starts_with('.')
+            && !domain.ends_with('.')
+            && !domain.contains("..");
+        if email.len() > 254 || local.is_empty() || !domain_valid || domain.contains('@') || email.chars().any(|c| c.is_whitespace()) {
+            return Err("Invalid email address");
+        }
+        Ok(())
+    }
+
+    pub fn validate_password(username: &str, password: &str) -> Result<(), &'static str> {
+        if password.len() < 8 || password.len() > 128 {
+            return Err("Password must be between 8 and 128 characters");
+        }
+        if !password.chars().any(|c| c.is_alphabetic()) || !password.chars().any(|c| c.is_ascii_digit()) {
+            return Err("Password must contain at least one letter and one digit");
+        }
+        if password.eq_ignore_ascii_case(username) {
+            return Err("Password must not match the username");
+        }
+        Ok(())
+    }
+
+    pub fn submit(&self, database: &Database) -> Result<RegisterStatus, Error> {
+        if let Err(reason) = self.validate() {
+            return Ok(RegisterStatus::Invalid(reason));
+        }
+
+        Login::new(self.username.clone(), self.password.clone())
+            .create(database, Some(self.email.clone()), self.name.clone(), self.site.clone())
+    }
+}
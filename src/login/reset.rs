@@ -0,0 +1,98 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::database::db::{Database, AQuery, GQuery};
+use crate::error::Error;
+use crate::login::encrypt::{Encrypt, Keys};
+use crate::login::login::{Login, User};
+use crate::login::register::Register;
+use crate::tools::utils::now;
+
+pub trait ResetDelivery: Send + Sync { // Hands a plaintext reset token to the user, e.g. by email
+    fn deliver(&self, user: &User, token: &str) -> Result<(), Error>;
+}
+
+pub struct Outbox { // Default delivery, appends one JSON line per reset so it works offline
+    pub path: PathBuf
+}
+
+impl ResetDelivery for Outbox {
+    fn deliver(&self, user: &User, token: &str) -> Result<(), Error> {
+        let line = serde_json::json!({
+            "timestamp": now(),
+            "username": user.username,
+            "email": user.email,
+            "token": token
+        });
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        writeln!(file, "{}", line)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ResetStatus {
+    Reset(String), // username
+    InvalidToken,
+    Invalid(&'static str)
+}
+
+pub struct Reset;
+
+impl Reset {
+    pub fn request(database: &Database, delivery: &dyn ResetDelivery, identifier: &str, lifetime: u64) -> Result<bool, Error> { // identifier is a username or email, false when neither matches
+        let user = match Login::user(database, identifier)? {
+            Some(user) => user,
+            None => {
+                let username = database.get::<String>(&GQuery::Email { email: identifier.to_string() })?
+                    .into_iter()
+                    .next()
+                    .and_then(|row| row.into_iter().next());
+                match username {
+                    Some(username) => match Login::user(database, &username)? {
+                        Some(user) => user,
+                        None => return Ok(false)
+                    },
+                    None => return Ok(false)
+                }
+            }
+        };
+
+        let token = Keys::token();
+        let query = AQuery::Reset {
+            token: Encrypt::sha256(&token),
+            username: user.username.clone(),
+            expires: (now() + lifetime) as i64
+        };
+        database.add_batch(&[AQuery::EndResets { username: user.username.clone() }, query])?;
+        delivery.deliver(&user, &token)?;
+        Ok(true)
+    }
+
+    pub fn confirm(database: &Database, token: &str, new_password: &str) -> Result<ResetStatus, Error> {
+        let query = GQuery::Reset {
+            token: Encrypt::sha256(token),
+            now: now() as i64
+        };
+        let username = match database.get::<String>(&query)?.into_iter().next().and_then(|row| row.into_iter().next()) {
+            Some(username) => username,
+            None => return Ok(ResetStatus::InvalidToken)
+        };
+        if let Err(reason) = Register::validate_password(&username, new_password) {
+            return Ok(ResetStatus::Invalid(reason));
+        }
+
+        database.add_batch(&[
+            AQuery::EndResets { username: username.clone() },
+            AQuery::Password { username: username.clone(), password: Encrypt::password(new_password)? },
+            AQuery::EndSessions { username: username.clone() },
+            AQuery::RevokeTokens { username: username.clone(), before: now() as i64 }, // Bearer tokens and API keys issued before the reset stop working too
+            AQuery::RevokeApiKeys { username: username.clone() }
+        ])?;
+        Ok(ResetStatus::Reset(username))
+    }
+}
@@ -0,0 +1,98 @@
+use crate::database::db::{Database, AQuery, GQuery};
+use crate::error::Error;
+use crate::login::encrypt::{Encrypt, Keys};
+use crate::tools::utils::now;
+
+pub const COOKIE_NAME: &str = "session";
+
+pub struct Session {
+    pub token: String, // Plaintext, only ever held by the client; the database stores its hash
+    pub username: String,
+    pub expires: u64
+}
+
+impl Session {
+    pub fn create(database: &Database, username: &str, lifetime: u64) -> Result<Self, Error> {
+        let session = Self {
+            token: Keys::token(),
+            username: username.to_string(),
+            expires: now() + lifetime
+        };
+
+        let query = AQuery::Session {
+            token: Encrypt::sha256(&session.token),
+            username: session.username.clone(),
+            created: now() as i64,
+            expires: session.expires as i64
+        };
+        database.add(&query)?;
+        Ok(session)
+    }
+
+    pub fn find(database: &Database, token: &str) -> Result<Option<String>, Error> { // token -> username
+        let query = GQuery::Session {
+            token: Encrypt::sha256(token),
+            now: now() as i64
+        };
+        let rows = database.get::<String>(&query)?;
+        Ok(rows.into_iter().next().and_then(|row| row.into_iter().next()))
+    }
+
+    pub fn end(database: &Database, token: &str) -> Result<(), Error> {
+        database.add(&AQuery::EndSession { token: Encrypt::sha256(token) })
+    }
+
+    pub fn cookie(&self) -> String {
+        format!(
+            "{}={}; Path=/; HttpOnly; SameSite=Lax; Max-Age={}",
+            COOKIE_NAME,
+            self.token,
+            self.expires.saturating_sub(now())
+        )
+    }
+
+    pub fn expired_cookie() -> String {
+        format!("{}=; Path=/; HttpOnly; SameSite=Lax; Max-Age=0", COOKIE_NAME)
+    }
+
+    pub fn from_cookie(header: &str) -> Option<String> {
+        header
+            .split(';')
+            .filter_map(|pair| pair.trim().split_once('='))
+            .find(|(name, _)| *name == COOKIE_NAME)
+            .map(|(_, value)| value.to_string())
+            .filter(|value| !value.is_empty())
+    }
+}
+
+pub struct Challenge; // Half finished logins waiting on a second factor
+
+impl Challenge {
+    pub fn create(database: &Database, username: &str, grant: &str, lifetime: u64) -> Result<String, Error> {
+        let token = Keys::token();
+        let query = AQuery::Challenge {
+            token: Encrypt::sha256(&token),
+            username: username.to_string(),
+            grant: grant.to_string(),
+            expires: (now() + lifetime) as i64
+        };
+        database.add(&query)?;
+        Ok(token)
+    }
+
+    pub fn find(database: &Database, token: &str) -> Result<Option<(String, String)>, Error> { // token -> (username, grant)
+        let query = GQuery::Challenge {
+            token: Encrypt::sha256(token),
+            now: now() as i64
+        };
+        let rows = database.get::<String>(&query)?;
+        Ok(rows.into_iter().next().and_then(|row| {
+            let mut row = row.into_iter();
+            Some((row.next()?, row.next()?))
+        }))
+    }
+
+    pub fn end(database: &Database, token: &str) -> Result<(), Error> {
+        database.add(&AQuery::EndChallenge { token: Encrypt::sha256(token) })
+    }
+}
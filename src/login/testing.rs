@@ -0,0 +1,38 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::database::db::{Database, DatabaseType};
+use crate::database::fields;
+use crate::database::schema::Schema;
+use crate::login::encrypt::Keys;
+use crate::login::keyring::{Key, Keyring};
+use crate::login::login::Login;
+use crate::login::{csrf, token, totp};
+
+pub struct Fixture { // An in-memory logins database with a throwaway keyring
+    pub database: Database,
+    pub keyring: Arc<Keyring>,
+    path: PathBuf
+}
+
+impl Fixture {
+    pub fn open() -> Self {
+        let path = std::env::temp_dir().join(format!("keyring-{}.json", &Keys::token()[..12]));
+        let keyring = Arc::new(Keyring::load(&path, Key::generate()).unwrap());
+        for name in [totp::KEY_NAME, csrf::KEY_NAME, token::KEY_NAME, fields::KEY_NAME, fields::INDEX_KEY_NAME] { // As Server::new
+            keyring.ensure(name).unwrap();
+        }
+        let database = Database::open(DatabaseType::Memory, Schema::logins(), false).unwrap().with_keyring(keyring.clone()).unwrap();
+        Self { database, keyring, path }
+    }
+
+    pub fn user(&self, username: &str, password: &str) {
+        Login::new(username.to_string(), password.to_string()).create(&self.database, None, None, None).unwrap();
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
@@ -0,0 +1,246 @@
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::database::db::{Database, AQuery, GQuery};
+use crate::error::Error;
+use crate::login::encrypt::{CipherError, Keys};
+use crate::login::keyring::Keyring;
+use crate::tools::utils::now;
+
+pub const KEY_NAME: &str = "signing_keys";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Claims {
+    pub iss: String,
+    pub sub: String,
+    pub iat: u64,
+    pub exp: u64
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    alg: String,
+    typ: String,
+    kid: String
+}
+
+pub struct Tokens; // HS256 JSON Web Tokens signed with keys from the signing_keys table, stored wrapped by the keyring
+
+impl Tokens {
+    pub fn issue(database: &Database, keyring: &Keyring, subject: &str, issuer: &str, lifetime: u64) -> Result<String, Error> {
+        let (kid, key) = match Self::active_key(database, keyring)? {
+            Some(active) => active,
+            None => {
+                Self::rotate(database, keyring)?;
+                Self::active_key(database, keyring)?.ok_or_else(|| Error::database("no active signing key"))?
+            }
+        };
+
+        let header = Header {
+            alg: String::from("HS256"),
+            typ: String::from("JWT"),
+            kid
+        };
+        let issued = now();
+        let claims = Claims {
+            iss: issuer.to_string(),
+            sub: subject.to_string(),
+            iat: issued,
+            exp: issued + lifetime
+        };
+
+        let signing_input = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&header)?),
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims)?)
+        );
+        let signature = Self::sign(&key, &signing_input).ok_or_else(|| Error::database("unusable signing key"))?;
+        Ok(format!("{}.{}", signing_input, URL_SAFE_NO_PAD.encode(signature)))
+    }
+
+    pub fn verify(database: &Database, keyring: &Keyring, token: &str, issuer: &str, lifetime: u64) -> Result<Option<Claims>, Error> { // None for a malformed, forged or expired token
+        let kid = match Self::kid(token) {
+            Some(kid) => kid,
+            None => return Ok(None)
+        };
+        let claims = match Self::key(database, keyring, &kid, lifetime)?.and_then(|key| Self::check(token, &key, issuer)) {
+            Some(claims) => claims,
+            None => return Ok(None)
+        };
+        let revoked = database.first::<(Option<i64>,)>(&GQuery::TokensRevoked { username: claims.sub.clone() })?; // Unset in textfile rows written before the column existed
+        match revoked {
+            Some((revoked,)) if claims.iat > revoked.unwrap_or(0).max(0) as u64 => Ok(Some(claims)),
+            _ => Ok(None) // Issued before a password reset, or the user is gone
+        }
+    }
+
+
+    pub fn rotate(database: &Database, keyring: &Keyring) -> Result<String, Error> { // Retires the active key; old tokens verify until they expire
+        let kid = Keys::token()[..16].to_string();
+        let current = now() as i64;
+        database.add_batch(&[
+            AQuery::RetireSigningKeys { retired: current },
+            AQuery::SigningKey {
+                kid: kid.clone(),
+                key: keyring.encrypt(KEY_NAME, &STANDARD.encode(Keys::new()), kid.as_bytes())?, // Bound to its kid so keys can't be swapped
+                created: current
+            }
+        ])?;
+        Ok(kid)
+    }
+
+    pub fn from_header(header: &str) -> Option<&str> {
+        let (scheme, token) = header.trim().split_once(' ')?;
+        if scheme.eq_ignore_ascii_case("Bearer") {
+            Some(token.trim())
+        } else {
+            None
+        }
+    }
+
+    fn kid(token: &str) -> Option<String> { // Read before the signature is checked, only to pick the key
+        let header: Header = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(token.split('.').next()?).ok()?).ok()?;
+        if header.alg != "HS256" {
+            return None;
+        }
+        Some(header.kid)
+    }
+
+    fn check(token: &str, key: &[u8], issuer: &str) -> Option<Claims> {
+        let mut parts = token.split('.');
+        let (header_part, claims_part, signature_part) = (parts.next()?, parts.next()?, parts.next()?);
+        if parts.next().is_some() {
+            return None;
+        }
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).ok()?;
+        mac.update(format!("{}.{}", header_part, claims_part).as_bytes());
+        mac.verify_slice(&URL_SAFE_NO_PAD.decode(signature_part).ok()?).ok()?;
+
+        let claims: Claims = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(claims_part).ok()?).ok()?;
+        if claims.iss != issuer || claims.exp <= now() || claims.sub.is_empty() {
+            return None;
+        }
+        Some(claims)
+    }
+
+    fn active_key(database: &Database, keyring: &Keyring) -> Result<Option<(String, Vec<u8>)>, Error> {
+        let rows = database.get::<String>(&GQuery::ActiveSigningKey)?;
+        let (kid, wrapped) = match rows.into_iter().next().and_then(|row| Some((row.first()?.clone(), row.get(1)?.clone()))) {
+            Some(active) => active,
+            None => return Ok(None)
+        };
+        let key = Self::unwrap(database, keyring, &kid, &wrapped)?;
+        Ok(Some((kid, key)))
+    }
+
+    fn key(database: &Database, keyring: &Keyring, kid: &str, lifetime: u64) -> Result<Option<Vec<u8>>, Error> {
+        let query = GQuery::SigningKey {
+            kid: kid.to_string(),
+            since: now().saturating_sub(lifetime) as i64
+        };
+        let rows = database.get::<String>(&query)?;
+        match rows.into_iter().next().and_then(|row| row.into_iter().next()) {
+            Some(wrapped) => Self::unwrap(database, keyring, kid, &wrapped).map(Some),
+            None => Ok(None)
+        }
+    }
+
+    fn unwrap(database: &Database, keyring: &Keyring, kid: &str, wrapped: &str) -> Result<Vec<u8>, Error> {
+        let decrypted = keyring.decrypt(wrapped, kid.as_bytes())?;
+        if let Some(rotated) = decrypted.rotated {
+            if let Err(e) = database.add(&AQuery::SigningKeyRewrap { kid: kid.to_string(), key: rotated }) { // Retried on the next read
+                println!("Failed to re-encrypt signing key {}: {}", kid, e);
+            }
+        }
+        Ok(STANDARD.decode(decrypted.plaintext).map_err(|_| CipherError::Encoding)?)
+    }
+
+    fn sign(key: &[u8], input: &str) -> Option<Vec<u8>> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).ok()?;
+        mac.update(input.as_bytes());
+        Some(mac.finalize().into_bytes().to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::login::testing::Fixture;
+
+    const ISSUER: &str = "test";
+
+    fn fixture() -> Fixture {
+        let fixture = Fixture::open();
+        fixture.user("bob", "Passw0rd1");
+        fixture
+    }
+
+    fn replace_part(token: &str, index: usize, part: &str) -> String {
+        let mut parts: Vec<&str> = token.split('.').collect();
+        parts[index] = part;
+        parts.join(".")
+    }
+
+    #[test]
+    fn round_trip() {
+        let f = fixture();
+        let token = Tokens::issue(&f.database, &f.keyring, "bob", ISSUER, 60).unwrap();
+        let claims = Tokens::verify(&f.database, &f.keyring, &token, ISSUER, 60).unwrap().unwrap();
+        assert_eq!(claims.sub, "bob");
+        assert_eq!(claims.exp, claims.iat + 60);
+        assert_eq!(Tokens::verify(&f.database, &f.keyring, &token, "other", 60).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_tampering() {
+        let f = fixture();
+        let token = Tokens::issue(&f.database, &f.keyring, "bob", ISSUER, 60).unwrap();
+        let claims = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&Claims { iss: ISSUER.to_string(), sub: String::from("admin"), iat: now(), exp: now() + 60 }).unwrap());
+        assert_eq!(Tokens::verify(&f.database, &f.keyring, &replace_part(&token, 1, &claims), ISSUER, 60).unwrap(), None);
+        assert_eq!(Tokens::verify(&f.database, &f.keyring, &replace_part(&token, 2, "AAAA"), ISSUER, 60).unwrap(), None);
+        assert_eq!(Tokens::verify(&f.database, &f.keyring, "not.a.token", ISSUER, 60).unwrap(), None);
+
+        let none = URL_SAFE_NO_PAD.encode(br#"{"alg":"none","typ":"JWT","kid":"x"}"#);
+        assert_eq!(Tokens::verify(&f.database, &f.keyring, &replace_part(&token, 0, &none), ISSUER, 60).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_unknown_kid() {
+        let f = fixture();
+        let token = Tokens::issue(&f.database, &f.keyring, "bob", ISSUER, 60).unwrap();
+        let header = URL_SAFE_NO_PAD.encode(br#"{"alg":"HS256","typ":"JWT","kid":"missing"}"#);
+        assert_eq!(Tokens::verify(&f.database, &f.keyring, &replace_part(&token, 0, &header), ISSUER, 60).unwrap(), None);
+    }
+
+    #[test]
+    fn fails_on_a_corrupted_key() { // Never mistaken for a missing key, which would quietly issue a new one
+        let f = fixture();
+        let kid = Tokens::rotate(&f.database, &f.keyring).unwrap();
+        f.database.add(&AQuery::SigningKeyRewrap { kid, key: String::from("not an envelope!") }).unwrap();
+        assert!(Tokens::issue(&f.database, &f.keyring, "bob", ISSUER, 60).is_err());
+    }
+
+    #[test]
+    fn rejects_expired() {
+        let f = fixture();
+        let token = Tokens::issue(&f.database, &f.keyring, "bob", ISSUER, 0).unwrap();
+        assert_eq!(Tokens::verify(&f.database, &f.keyring, &token, ISSUER, 60).unwrap(), None);
+    }
+
+    #[test]
+    fn survives_rotation_until_revoked() {
+        let f = fixture();
+        let token = Tokens::issue(&f.database, &f.keyring, "bob", ISSUER, 60).unwrap();
+        Tokens::rotate(&f.database, &f.keyring).unwrap();
+        f.keyring.rotate(KEY_NAME).unwrap(); // The stored key is rewrapped on its next read
+        assert!(Tokens::verify(&f.database, &f.keyring, &token, ISSUER, 60).unwrap().is_some());
+        assert_ne!(Tokens::issue(&f.database, &f.keyring, "bob", ISSUER, 60).unwrap(), token);
+
+        f.database.add(&AQuery::RevokeTokens { username: String::from("bob"), before: now() as i64 }).unwrap();
+        assert_eq!(Tokens::verify(&f.database, &f.keyring, &token, ISSUER, 60).unwrap(), None);
+    }
+}
@@ -0,0 +1,253 @@
+use aes_gcm::aead::OsRng;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha1::Sha1;
+
+use crate::database::db::{Database, AQuery, GQuery};
+use crate::error::{AuthError, Error};
+use crate::login::encrypt::{Encrypt, Keys};
+use crate::login::keyring::Keyring;
+use crate::tools::utils::now;
+
+const STEP: u64 = 30;
+const DIGITS: u32 = 6;
+const RECOVERY_CODES: usize = 10;
+const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+pub const KEY_NAME: &str = "totp";
+
+pub struct Totp; // RFC 6238 time-based one time passwords (HMAC-SHA1, 30s steps, 6 digits)
+
+pub struct Enrolment {
+    pub secret: String, // base32, for manual entry
+    pub uri: String // otpauth://, for QR codes
+}
+
+impl Totp {
+    pub fn enroll(database: &Database, keyring: &Keyring, username: &str, issuer: &str) -> Result<Enrolment, Error> {
+        let mut secret = [0u8; 20];
+        OsRng.fill_bytes(&mut secret);
+        let encoded = Self::base32(&secret);
+
+        let query = AQuery::TotpSecret {
+            username: username.to_string(),
+            secret: keyring.encrypt(KEY_NAME, &encoded, username.as_bytes())? // Bound to the user so it can't be copied to another row
+        };
+        database.add(&query)?;
+
+        let uri = format!(
+            "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+            Self::escape(issuer),
+            Self::escape(username),
+            encoded,
+            Self::escape(issuer),
+            DIGITS,
+            STEP
+        );
+        Ok(Enrolment { secret: encoded, uri })
+    }
+
+    pub fn confirm(database: &Database, keyring: &Keyring, username: &str, code: &str) -> Result<Vec<String>, Error> { // -> recovery codes, shown once
+        if Self::enabled(database, username)? || !Self::check_code(database, keyring, username, code)? {
+            return Err(AuthError::InvalidCode.into());
+        }
+        database.transaction(|database| { // Enabled only together with its recovery codes
+            database.add(&AQuery::TotpEnable { username: username.to_string() })?;
+            Self::regenerate_recovery_codes(database, username)
+        })
+    }
+
+    pub fn disable(database: &Database, username: &str) -> Result<(), Error> {
+        database.add_batch(&[
+            AQuery::TotpDisable { username: username.to_string() },
+            AQuery::ClearRecoveryCodes { username: username.to_string() }
+        ])
+    }
+
+    pub fn enabled(database: &Database, username: &str) -> Result<bool, Error> {
+        Ok(Self::state(database, username)?.is_some_and(|(enabled, _)| enabled))
+    }
+
+    pub fn verify(database: &Database, keyring: &Keyring, username: &str, code: &str) -> Result<bool, Error> { // Accepts a TOTP or a recovery code
+        if !Self::enabled(database, username)? {
+            return Ok(false);
+        }
+        let code = code.trim();
+        if code.len() == DIGITS as usize && code.chars().all(|c| c.is_ascii_digit()) {
+            Self::check_code(database, keyring, username, code)
+        } else {
+            Self::use_recovery_code(database, username, code)
+        }
+    }
+
+    pub fn regenerate_recovery_codes(database: &Database, username: &str) -> Result<Vec<String>, Error> { // The old codes stay valid if the new ones can't all be stored
+        let mut codes = Vec::with_capacity(RECOVERY_CODES);
+        let mut queries = vec![AQuery::ClearRecoveryCodes { username: username.to_string() }];
+        for _ in 0..RECOVERY_CODES {
+            let token = Keys::token();
+            let code = format!("{}-{}-{}-{}", &token[..5], &token[5..10], &token[10..15], &token[15..20]); // 80 bits, out of reach offline even though the hash is unkeyed
+            queries.push(AQuery::RecoveryCode {
+                username: username.to_string(),
+                code: Encrypt::sha256(&code)
+            });
+            codes.push(code);
+        }
+        database.add_batch(&queries)?;
+        Ok(codes)
+    }
+
+    pub fn code(secret: &[u8], step: u64) -> Option<u32> { // RFC 4226 HOTP with dynamic truncation
+        let mut mac = Hmac::<Sha1>::new_from_slice(secret).ok()?;
+        mac.update(&step.to_be_bytes());
+        let hash = mac.finalize().into_bytes();
+        let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+        let binary = u32::from_be_bytes([hash[offset] & 0x7f, hash[offset + 1], hash[offset + 2], hash[offset + 3]]);
+        Some(binary % 10u32.pow(DIGITS))
+    }
+
+    fn check_code(database: &Database, keyring: &Keyring, username: &str, code: &str) -> Result<bool, Error> {
+        let (secret, last_step) = match (Self::secret(database, keyring, username)?, Self::state(database, username)?) {
+            (Some(secret), Some((_, last_step))) => (secret, last_step),
+            _ => return Ok(false)
+        };
+        let code = code.trim();
+        let current = now() / STEP;
+
+        for step in [current.saturating_sub(1), current, current + 1] {
+            if step <= last_step {
+                continue; // Already used, replay
+            }
+            let expected = match Self::code(&secret, step) {
+                Some(expected) => format!("{:0width$}", expected, width = DIGITS as usize),
+                None => return Ok(false)
+            };
+            if Encrypt::constant_eq(expected.as_bytes(), code.as_bytes()) {
+                database.add(&AQuery::TotpStep { username: username.to_string(), step: step as i64 })?;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn use_recovery_code(database: &Database, username: &str, code: &str) -> Result<bool, Error> {
+        let hash = Encrypt::sha256(&code.to_ascii_lowercase());
+        let query = GQuery::RecoveryCode {
+            username: username.to_string(),
+            code: hash.clone()
+        };
+        if database.get::<String>(&query)?.is_empty() {
+            return Ok(false);
+        }
+        database.add(&AQuery::UseRecoveryCode { username: username.to_string(), code: hash })?;
+        Ok(true)
+    }
+
+    fn secret(database: &Database, keyring: &Keyring, username: &str) -> Result<Option<Vec<u8>>, Error> {
+        let rows = database.get::<Option<String>>(&GQuery::TotpSecret { username: username.to_string() })?;
+        let ciphertext = match rows.into_iter().next().and_then(|row| row.into_iter().next()).flatten() {
+            Some(ciphertext) => ciphertext,
+            None => return Ok(None)
+        };
+        let decrypted = match keyring.decrypt(&ciphertext, username.as_bytes()) {
+            Ok(decrypted) => decrypted,
+            Err(error) => {
+                println!("Could not decrypt TOTP secret for {}: {}", username, error);
+                return Ok(None);
+            }
+        };
+        if let Some(rotated) = decrypted.rotated {
+            if let Err(e) = database.add(&AQuery::TotpRewrap { username: username.to_string(), secret: rotated }) { // Retried on the next read
+                println!("Failed to re-encrypt TOTP secret for {}: {}", username, e);
+            }
+        }
+        Ok(Self::from_base32(&decrypted.plaintext))
+    }
+
+    fn state(database: &Database, username: &str) -> Result<Option<(bool, u64)>, Error> { // -> (enabled, last used step)
+        let rows = database.get::<i64>(&GQuery::TotpState { username: username.to_string() })?;
+        Ok(rows.first().map(|row| (row[0] != 0, row[1].max(0) as u64)))
+    }
+
+    fn base32(bytes: &[u8]) -> String { // RFC 4648, unpadded
+        let mut output = String::new();
+        for chunk in bytes.chunks(5) {
+            let mut buffer = [0u8; 5];
+            buffer[..chunk.len()].copy_from_slice(chunk);
+            let value = buffer.iter().fold(0u64, |acc, byte| (acc << 8) | *byte as u64);
+            let characters = (chunk.len() * 8).div_ceil(5);
+            for i in 0..characters {
+                output.push(BASE32[((value >> (35 - i * 5)) & 0x1f) as usize] as char);
+            }
+        }
+        output
+    }
+
+    fn from_base32(encoded: &str) -> Option<Vec<u8>> {
+        let mut output = Vec::new();
+        let (mut buffer, mut bits) = (0u64, 0u32);
+        for c in encoded.trim_end_matches('=').chars() {
+            let value = BASE32.iter().position(|b| *b as char == c.to_ascii_uppercase())? as u64;
+            buffer = (buffer << 5) | value;
+            bits += 5;
+            if bits >= 8 {
+                bits -= 8;
+                output.push((buffer >> bits) as u8);
+                buffer &= (1 << bits) - 1;
+            }
+        }
+        Some(output)
+    }
+
+    fn escape(value: &str) -> String {
+        value
+            .bytes()
+            .map(|byte| match byte {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+                _ => format!("%{:02X}", byte)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::login::testing::Fixture;
+
+    fn current_code(secret: &str) -> String {
+        let code = Totp::code(&Totp::from_base32(secret).unwrap(), now() / STEP).unwrap();
+        format!("{:06}", code)
+    }
+
+    #[test]
+    fn codes_work_once() {
+        let f = Fixture::open();
+        f.user("bob", "Passw0rd1");
+        let enrolment = Totp::enroll(&f.database, &f.keyring, "bob", "test").unwrap();
+        let code = current_code(&enrolment.secret);
+        let recovery = Totp::confirm(&f.database, &f.keyring, "bob", &code).unwrap();
+        assert_eq!(recovery.len(), RECOVERY_CODES);
+        assert!(recovery.iter().all(|code| code.chars().filter(char::is_ascii_hexdigit).count() >= 16));
+
+        assert!(!Totp::verify(&f.database, &f.keyring, "bob", &code).unwrap()); // Spent by confirming
+        assert!(Totp::verify(&f.database, &f.keyring, "bob", &recovery[0]).unwrap());
+        assert!(!Totp::verify(&f.database, &f.keyring, "bob", &recovery[0]).unwrap());
+        assert!(Totp::verify(&f.database, &f.keyring, "bob", &recovery[1].to_ascii_uppercase()).unwrap());
+        assert!(!Totp::verify(&f.database, &f.keyring, "bob", "00000-00000-00000-00000").unwrap());
+    }
+
+    #[test]
+    fn rfc6238_vectors() { // Appendix B, SHA-1, truncated to our 6 digits
+        let secret = b"12345678901234567890";
+        let vectors = [
+            (59, 287082),
+            (1111111109, 81804),
+            (1111111111, 50471),
+            (1234567890, 5924),
+            (2000000000, 279037),
+            (20000000000, 353130)
+        ];
+        for (time, expected) in vectors {
+            assert_eq!(Totp::code(secret, time / STEP), Some(expected), "T = {}", time);
+        }
+    }
+}
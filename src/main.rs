@@ -1,6 +1,45 @@
+use std::path::Path;
+use std::process::exit;
+
 use simple_tcp_server::Server;
 
 fn main() {
-    let mut web_server = Server::from_presets();
-    web_server.start();
-}
\ No newline at end of file
+    let args: Vec<String> = std::env::args().collect();
+    let mut web_server = match Server::from_presets() {
+        Ok(web_server) => web_server,
+        Err(e) => {
+            println!("Failed to start: {}", e);
+            exit(1);
+        }
+    };
+    match args.get(1).map(|command| command.as_str()) {
+        Some("backup") => match web_server.backup_databases() {
+            Ok(paths) => {
+                for path in paths {
+                    println!("Backed up to {}", path.display());
+                }
+            },
+            Err(e) => {
+                println!("Backup failed: {}", e);
+                exit(1);
+            }
+        },
+        Some("restore") => match (args.get(2), args.get(3)) {
+            (Some(name), Some(source)) => match web_server.restore_database(name, Path::new(source)) {
+                Ok(()) => println!("Restored {} from {}", name, source),
+                Err(e) => {
+                    println!("Restore failed: {}", e);
+                    exit(1);
+                }
+            },
+            _ => {
+                println!("Usage: restore <logins|logs> <backup file>");
+                exit(2);
+            }
+        },
+        _ => if let Err(e) = web_server.start() {
+            println!("Server stopped: {}", e);
+            exit(1);
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::database::access::AccessEntry;
+use crate::database::db::{AQuery, Database};
+use crate::tools::utils::now;
+
+const QUEUE_LENGTH: usize = 10_000; // Entries waiting for the writer before new ones are dropped
+const PRUNE_SECONDS: u64 = 3600;
+
+pub struct AccessLog { // Requests are queued here and written in batches by a background thread
+    sender: Option<SyncSender<AccessEntry>>,
+    worker: Option<JoinHandle<()>>
+}
+
+impl AccessLog {
+    pub fn start(database: Arc<Database>, batch_size: usize, flush_interval: Duration, retention_seconds: u64) -> Self { // 0 retention keeps every row
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_LENGTH);
+        let worker = thread::Builder::new()
+            .name(String::from("access-log"))
+            .spawn(move || Self::run(receiver, database, batch_size.max(1), flush_interval, retention_seconds));
+        match worker {
+            Ok(worker) => Self { sender: Some(sender), worker: Some(worker) },
+            Err(e) => {
+                println!("Failed to start the access log writer: {}", e);
+                Self { sender: None, worker: None }
+            }
+        }
+    }
+
+    pub fn record(&self, entry: AccessEntry) { // Never waits on the database, drops the entry if the queue is full
+        if let Some(sender) = &self.sender {
+            match sender.try_send(entry) {
+                Ok(()) => {},
+                Err(TrySendError::Full(_)) => println!("Access log queue full, dropping entry"),
+                Err(TrySendError::Disconnected(_)) => println!("Access log writer stopped")
+            }
+        }
+    }
+
+    fn run(receiver: Receiver<AccessEntry>, database: Arc<Database>, batch_size: usize, flush_interval: Duration, retention_seconds: u64) {
+        let mut batch: Vec<AQuery> = Vec::with_capacity(batch_size);
+        let mut deadline = Instant::now() + flush_interval;
+        let mut next_prune = 0;
+        loop {
+            let received = receiver.recv_timeout(deadline.saturating_duration_since(Instant::now()));
+            let disconnected = matches!(received, Err(RecvTimeoutError::Disconnected));
+            if let Ok(entry) = received {
+                batch.push(entry.as_query());
+            }
+
+            if !batch.is_empty() && (batch.len() >= batch_size || Instant::now() >= deadline || disconnected) {
+                if let Err(e) = database.add_batch(&batch) {
+                    println!("Failed to write {} access log entries: {}", batch.len(), e);
+                }
+                batch.clear();
+            }
+            if Instant::now() >= deadline {
+                deadline = Instant::now() + flush_interval;
+            }
+            if retention_seconds > 0 && now() >= next_prune {
+                if let Err(e) = database.add(&AQuery::PruneAccess { before: now().saturating_sub(retention_seconds) as i64 }) {
+                    println!("Failed to prune the access log: {}", e);
+                }
+                next_prune = now() + PRUNE_SECONDS;
+            }
+            if disconnected {
+                return;
+            }
+        }
+    }
+}
+
+impl Drop for AccessLog {
+    fn drop(&mut self) { // Flushes whatever is still queued
+        self.sender.take();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
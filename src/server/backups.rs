@@ -0,0 +1,48 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::database::db::Database;
+
+pub struct BackupSchedule { // Backs every database up on an interval from a background thread
+    stop: Option<Sender<()>>,
+    worker: Option<JoinHandle<()>>
+}
+
+impl BackupSchedule {
+    pub fn start(databases: Vec<Arc<Database>>, dir: PathBuf, interval: Duration, keep: usize) -> Self { // The first backup is taken one interval after start
+        let (stop, receiver) = mpsc::channel();
+        let worker = thread::Builder::new()
+            .name(String::from("backups"))
+            .spawn(move || Self::run(receiver, databases, dir, interval, keep));
+        match worker {
+            Ok(worker) => Self { stop: Some(stop), worker: Some(worker) },
+            Err(e) => {
+                println!("Failed to start scheduled backups: {}", e);
+                Self { stop: None, worker: None }
+            }
+        }
+    }
+
+    fn run(receiver: Receiver<()>, databases: Vec<Arc<Database>>, dir: PathBuf, interval: Duration, keep: usize) {
+        while let Err(RecvTimeoutError::Timeout) = receiver.recv_timeout(interval) {
+            for database in &databases {
+                match database.backup(&dir, keep) {
+                    Ok(path) => println!("Backed up {} to {}", database.name, path.display()),
+                    Err(e) => println!("Failed to back up {}: {}", database.name, e)
+                }
+            }
+        }
+    }
+}
+
+impl Drop for BackupSchedule {
+    fn drop(&mut self) { // Waits for a backup in progress, then stops
+        self.stop.take();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
@@ -1 +1,3 @@
-pub mod response;
\ No newline at end of file
+pub mod access_log;
+pub mod backups;
+pub mod response;
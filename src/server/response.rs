@@ -6,7 +6,8 @@ pub struct Response<'a> {
     pub status_line: String,
     pub contents: String,
     pub response_data: String,
-    pub response_status: ResponseStatus
+    pub response_status: ResponseStatus,
+    pub headers: Vec<(String, String)>
 }
 
 #[derive(Debug)]
@@ -21,19 +22,26 @@ impl<'a> Response<'a> {
     pub fn new(filesystem: &'a FileSystem) -> Self {
         let empty_string = String::new();
         Self {
-            filesystem: filesystem,
+            filesystem,
             status_line: empty_string.clone(),
             contents: empty_string.clone(),
             response_data: empty_string,
-            response_status: ResponseStatus::Unknown
+            response_status: ResponseStatus::Unknown,
+            headers: Vec::new()
         }
     }
 
     pub fn format_file(&mut self, string_path: String) {
+        self.format_template(string_path, &[]);
+    }
+
+    pub fn format_template(&mut self, string_path: String, values: &[(&str, &str)]) { // Fills {{placeholders}} in the file
         self.status_line = String::from("HTTP/1.1 200 OK");
         match self.filesystem.get_template(string_path) {
             Some(contents) => {
-                self.contents = contents;
+                self.contents = values
+                    .iter()
+                    .fold(contents, |contents, (placeholder, value)| contents.replace(placeholder, value));
                 self.response_data = Self::format_response(self);
                 self.response_status = ResponseStatus::Ok;
             },
@@ -51,28 +59,67 @@ impl<'a> Response<'a> {
         self.response_status = ResponseStatus::Ok;
     }
 
+    pub fn format_json(&mut self, body: &serde_json::Value) {
+        self.format_status(&body.to_string());
+        self.add_header("Content-Type", "application/json");
+    }
+
     pub fn format_404(&mut self) {
         self.response_data = Self::error(self, 404, "NOT FOUND");
     }
 
     pub fn format_error(&mut self, error_type: usize, error_msg: &str) {
         self.response_status = match error_type {
-            403 => ResponseStatus::Denied,
+            401 | 403 => ResponseStatus::Denied,
             _ => ResponseStatus::Failed
         };
         self.response_data = Self::error(self, error_type, error_msg);
     } 
 
+    pub fn format_login_redirect(&mut self, location: &str) { // 401 that browsers follow to the login page
+        self.format_error(401, "Unauthorized");
+        self.contents = format!(
+            "<!DOCTYPE html><html><head><meta http-equiv=\"refresh\" content=\"0; url={0}\"></head><body><a href=\"{0}\">Log in</a></body></html>",
+            location
+        );
+        self.add_header("Content-Type", "text/html");
+        self.add_header("Location", location);
+    }
+
+    pub fn add_header(&mut self, name: &str, value: &str) {
+        self.headers.push((name.to_string(), value.to_string()));
+        self.response_data = Self::format_response(self);
+    }
+
+    pub fn format_error_detail(&mut self, error_type: usize, error_msg: &str, detail: &str) {
+        self.format_error(error_type, error_msg);
+        self.contents = detail.to_string();
+        self.response_data = Self::format_response(self);
+    }
+
+    pub fn status_code(&self) -> u16 { // Read back from the status line, 0 before one is set
+        self.status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|code| code.parse().ok())
+            .unwrap_or(0)
+    }
+
     fn error(&mut self, error_type: usize, error_msg: &str) -> String {
         self.status_line = format!("HTTP/1.1 {} {}", error_type, error_msg);
         self.contents = error_msg.to_string();
-        Self::format_response(&self)
+        Self::format_response(self)
     }
 
     fn format_response(&self) -> String {
+        let headers: String = self.headers
+            .iter()
+            .map(|(name, value)| format!("{}: {}\r\n", name, value))
+            .collect();
         format!(
-            "{}\r\nContent-Length: {}\r\n\r\n{}",
+            "{}\r\n{}Content-Length: {}\r\n\r\n{}",
             self.status_line,
+            headers,
             self.contents.len(),
             self.contents
         )
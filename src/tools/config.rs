@@ -1,9 +1,8 @@
 use serde_json::Value;
 use std::collections::HashMap;
-use std::default;
-use std::error::Error;
 use std::path::Path;
 use std::sync::OnceLock;
+use crate::error::Error;
 use crate::tools::filesystem::FileSystem;
 use lazy_static::lazy_static;
 
@@ -28,8 +27,33 @@ impl DType {
 
 lazy_static! { // Handling runtime-initialized static data
     pub static ref DATA: Vec<(String, Value, DType)> = vec![
-        ("auto_reset".to_string(), Value::Bool(true), DType::Bool),
+        ("database_type".to_string(), Value::from("sqlite"), DType::String),
+        ("database_fixture".to_string(), Value::from(""), DType::String),
+        ("database_pool_size".to_string(), Value::from(4), DType::Integer),
+        ("database_busy_timeout_ms".to_string(), Value::from(5000), DType::Integer),
+        ("database_checkout_timeout_ms".to_string(), Value::from(2000), DType::Integer),
+        ("request_timeout_ms".to_string(), Value::from(10000), DType::Integer),
+        ("max_request_bytes".to_string(), Value::from(1048576), DType::Integer),
+        ("backup_dir".to_string(), Value::from("db/backups"), DType::String),
+        ("backup_interval_hours".to_string(), Value::from(24), DType::Integer),
+        ("backup_keep".to_string(), Value::from(7), DType::Integer),
+        ("migrations_dry_run".to_string(), Value::Bool(false), DType::Bool),
         ("debug".to_string(), Value::Bool(false), DType::Bool),
+        ("registration".to_string(), Value::Bool(true), DType::Bool),
+        ("max_login_attempts".to_string(), Value::from(5), DType::Integer),
+        ("lockout_seconds".to_string(), Value::from(900), DType::Integer),
+        ("session_seconds".to_string(), Value::from(86400), DType::Integer),
+        ("login_page".to_string(), Value::from("login.html"), DType::String),
+        ("token_issuer".to_string(), Value::from("simple-tcp-server"), DType::String),
+        ("token_seconds".to_string(), Value::from(3600), DType::Integer),
+        ("key_file".to_string(), Value::from("db/server.key"), DType::String),
+        ("keyring_file".to_string(), Value::from("db/keyring.json"), DType::String),
+        ("reset_seconds".to_string(), Value::from(3600), DType::Integer),
+        ("outbox_file".to_string(), Value::from("outbox/resets.jsonl"), DType::String),
+        ("access_log".to_string(), Value::Bool(true), DType::Bool),
+        ("access_log_batch".to_string(), Value::from(100), DType::Integer),
+        ("access_log_flush_ms".to_string(), Value::from(1000), DType::Integer),
+        ("access_log_retention_days".to_string(), Value::from(30), DType::Integer),
     ];
 }
 
@@ -37,30 +61,21 @@ fn default_config() -> HashMap<String, Value> {
     println!("Falling back to default");
     let mut presets = HashMap::new();
 
-    for (key, value, dtype) in DATA.iter() {
+    for (key, value, _) in DATA.iter() {
         presets.insert(key.clone(), value.clone());
     }
 
     presets
 }
 
-pub fn load_config(path: &str) {
+pub fn load_config(path: &str) -> Result<(), Error> { // A missing file runs on the presets, a malformed one is refused
     let config = match FileSystem::read_file(Path::new(path)) {
-        Ok(data) => match serde_json::from_str::<HashMap<String, Value>>(&data) {
-            Ok(json) => json,
-            Err(_) => {
-                default_config()
-            }
-        },
-        Err(_) => {
-            default_config()
-        },
+        Ok(data) => serde_json::from_str::<HashMap<String, Value>>(&data).map_err(|e| Error::Config(format!("{}: {}", path, e)))?,
+        Err(_) => default_config()
     };
-    if validate_config(&config) {
-        CONFIG.set(config);
-    } else {
-        CONFIG.set(default_config());
-    }
+    validate_config(&config).map_err(|reason| Error::Config(format!("{}: {}", path, reason)))?;
+    let _ = CONFIG.set(config); // Already loaded by an earlier server in this process
+    Ok(())
 }
 
 pub fn get_config(query: &str) -> Option<Value> {
@@ -71,38 +86,45 @@ pub fn get_config(query: &str) -> Option<Value> {
     }
 }
 
-fn validate_config(config: &HashMap<String, Value>) -> bool {
-    for (key, value, dtype) in DATA.iter() {
-        match config.get(key) {
-            Some(value) => {
-                match value {
-                    Value::String(ref s) => {
-                        if *dtype != DType::String {
-                            return false;
-                        }
-                    },
-                    Value::Bool(b) => {
-                        if *dtype != DType::Bool {
-                            return false;
-                        }
-                    },
-                    Value::Number(n) => {
-                        if let Some(i) = n.as_u64() {
-                            if *dtype != DType::Integer {
-                                return false;
-                            }
-                        } else {
-                            return false;
-                        }
-                    },
-                    _ => {
-                        return false;
-                    }
-                }
+pub fn get_bool(query: &str) -> bool { // Falls back to the preset in DATA when unset
+    match get_config(query) {
+        Some(value) => value.as_bool().unwrap_or(false),
+        None => preset(query).and_then(|value| value.as_bool()).unwrap_or(false)
+    }
+}
+
+pub fn get_integer(query: &str) -> u64 {
+    match get_config(query) {
+        Some(value) => value.as_u64().unwrap_or(0),
+        None => preset(query).and_then(|value| value.as_u64()).unwrap_or(0)
+    }
+}
 
-            },
-            None => {return false}
+pub fn get_string(query: &str) -> String {
+    match get_config(query) {
+        Some(value) => value.as_str().unwrap_or_default().to_string(),
+        None => preset(query).and_then(|value| value.as_str().map(|s| s.to_string())).unwrap_or_default()
+    }
+}
+
+fn preset(query: &str) -> Option<Value> {
+    DATA.iter()
+        .find(|(key, _, _)| key == query)
+        .map(|(_, value, _)| value.clone())
+}
+
+fn validate_config(config: &HashMap<String, Value>) -> Result<(), String> { // -> the first key of the wrong type, missing keys read as their preset
+    for (key, _, dtype) in DATA.iter() {
+        let valid = match config.get(key) {
+            Some(Value::String(_)) => *dtype == DType::String,
+            Some(Value::Bool(_)) => *dtype == DType::Bool,
+            Some(Value::Number(n)) => n.as_u64().is_some() && *dtype == DType::Integer,
+            Some(_) => false,
+            None => true
+        };
+        if !valid {
+            return Err(format!("{} should be {:?}", key, dtype));
         }
     }
-    true
-}
\ No newline at end of file
+    Ok(())
+}
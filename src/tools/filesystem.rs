@@ -1,6 +1,7 @@
 use std::env;
 use std::path::{Path, PathBuf};
-use std::error::Error;
+
+use crate::error::Error;
 
 use std::collections::HashMap;
 
@@ -21,26 +22,17 @@ impl FileSystem {
     }
 
     pub fn get_template(&self, string_path: String) -> Option<String> {
-        let file_dir = Self::check_file_extension(&self, &string_path);
-        let path = match Self::check_file_availability(string_path, file_dir) {
-            Some(path) => path,
-            None => return None
-        };
-        match Self::read_file(&path) {
-            Ok(file_contents) => Some(file_contents),
-            Err(_) => None
-        }
+        let file_dir = Self::check_file_extension(self, &string_path);
+        let path = Self::check_file_availability(string_path, file_dir)?;
+        Self::read_file(&path).ok()
     }
     
-    fn check_file_extension(&self, string_path: &String) -> String{
-        let file_ext: &str = match string_path.split(".").last() {
-            Some(file_ext) => file_ext,
-            None => ""
-        };
+    fn check_file_extension(&self, string_path: &str) -> String{
+        let file_ext: &str = string_path.split('.').next_back().unwrap_or_default();
         match self.allowed_ext.get(file_ext) {
-            Some(path) => return path.clone(),
-            None => return String::from("public")
-        };
+            Some(path) => path.clone(),
+            None => String::from("public")
+        }
     }
 
     pub fn check_file_availability(string_path: String, file_dir: String) -> Option<PathBuf> {
@@ -50,15 +42,27 @@ impl FileSystem {
             Err(_) => return None,
         };
         if path.exists() {
-            return Some(path);
+            Some(path)
         } else {
-            return None;
+            None
+        }
+    }
+
+    pub fn normalize(string_path: &str) -> Option<String> { // Rejects any attempt to climb out of the served folders
+        let mut segments = Vec::new();
+        for segment in string_path.split('/') {
+            match segment {
+                "" | "." => {},
+                ".." => return None,
+                _ if segment.contains('\\') => return None,
+                _ => segments.push(segment)
+            }
         }
+        Some(segments.join("/"))
     }
 
-    pub fn read_file(file_name: &Path) -> Result<String, Box<dyn Error>> {
+    pub fn read_file(file_name: &Path) -> Result<String, Error> {
         //println!("FILE PATH: {}", file_path.clone().display());
-        std::fs::read_to_string(file_name)
-            .map_err(|e| Box::new(e) as Box<dyn Error>) // Maps any errors to Box<dyn Error>
+        Ok(std::fs::read_to_string(file_name)?)
     }
 }
\ No newline at end of file
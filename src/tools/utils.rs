@@ -1,11 +1,12 @@
-pub fn get_user_input() -> String {
+use crate::error::Error;
+
+pub fn get_user_input() -> Result<String, Error> {
     use std::io::{stdin, stdout, Write};
     let mut input = String::new();
     let _ = stdout().flush();
 
     stdin()
-        .read_line(&mut input)
-        .expect("An error occured");
+        .read_line(&mut input)?;
 
     //clean string
     if let Some('\n') = input.chars().next_back() {
@@ -16,5 +17,27 @@ pub fn get_user_input() -> String {
         input.pop();
     }
 
-    input
-}
\ No newline at end of file
+    Ok(input)
+}
+
+pub fn now() -> u64 { // Unix timestamp in seconds
+    use std::time::{SystemTime, UNIX_EPOCH};
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+pub fn utc_stamp(timestamp: u64) -> String { // 20240131T235959Z, sorts in time order
+    let (days, seconds) = (timestamp / 86400, timestamp % 86400);
+    let shifted = days as i64 + 719468; // Days since 0000-03-01, so leap days fall at the end of a year
+    let era = shifted.div_euclid(146097);
+    let day_of_era = shifted.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}{:02}{:02}T{:02}{:02}{:02}Z", year, month, day, seconds / 3600, seconds % 3600 / 60, seconds % 60)
+}